@@ -1,2 +1,54 @@
 // System call numbers
+#define SYS_fork    1
+#define SYS_exit    2
+#define SYS_wait    3
+#define SYS_pipe    4
+#define SYS_read    5
+#define SYS_kill    6
 #define SYS_exec    7
+#define SYS_fstat   8
+#define SYS_chdir   9
+#define SYS_dup    10
+#define SYS_getpid 11
+#define SYS_sbrk   12
+#define SYS_sleep  13
+#define SYS_uptime 14
+#define SYS_open   15
+#define SYS_write  16
+#define SYS_mknod  17
+#define SYS_unlink 18
+#define SYS_link   19
+#define SYS_mkdir  20
+#define SYS_close  21
+#define SYS_mmap   22
+#define SYS_munmap 23
+#define SYS_sigalarm  24
+#define SYS_sigreturn 25
+#define SYS_sigaction    26
+#define SYS_sigprocmask  27
+#define SYS_rt_sigreturn 28
+#define SYS_clone  29
+#define SYS_join   30
+#define SYS_settickets 31
+#define SYS_getticks   32
+#define SYS_sched_setaffinity 33
+#define SYS_sched_getaffinity 34
+#define SYS_nanosleep      35
+#define SYS_clock_gettime  36
+#define SYS_futex          37
+#define SYS_shmget         38
+#define SYS_shmat          39
+#define SYS_shmdt          40
+#define SYS_shmctl         41
+#define SYS_endpoint_create 42
+#define SYS_send           43
+#define SYS_recv           44
+#define SYS_call           45
+#define SYS_reply          46
+#define SYS_socket         47
+#define SYS_bind           48
+#define SYS_listen         49
+#define SYS_accept         50
+#define SYS_connect        51
+#define SYS_sendto         52
+#define SYS_recvfrom       53
@@ -1,8 +1,12 @@
 use crate::consts::CONSOLE_BUF as INPUT_BUF;
+use crate::mm::Address;
+use crate::process::{CPU_MANAGER, PROC_MANAGER};
 use crate::spinlock::SpinLock;
 
 mod uart;
 
+pub use uart::uartintr;
+
 struct Cons {
     buf: [u8; INPUT_BUF],
     r: usize, // Read index
@@ -20,13 +24,20 @@ static CONS: SpinLock<Cons> = SpinLock::new(
     "cons",
 );
 
-// fn consputbs() {
-//     // b'\b' not supported in rust
-//     const BACKSPACE: u8 = 8;
-//     uart::uartputc(BACKSPACE);
-//     uart::uartputc(b' ');
-//     uart::uartputc(BACKSPACE);
-// }
+/// Control-x
+const fn ctrl(x: u8) -> u8 {
+    x - b'@'
+}
+
+// b'\b' not supported in rust
+const BACKSPACE: u8 = 8;
+const DELETE: u8 = 0x7f;
+
+fn consputbs() {
+    uart::uartputc(BACKSPACE);
+    uart::uartputc(b' ');
+    uart::uartputc(BACKSPACE);
+}
 
 pub fn consputc(c: u8) {
     uart::uartputc(c);
@@ -36,3 +47,115 @@ pub fn consputc(c: u8) {
 pub unsafe fn consoleinit() {
     uart::uartinit();
 }
+
+/// User write to the console.
+/// Return the number of bytes written.
+pub fn consolewrite(src: Address, count: u32) -> Result<u32, &'static str> {
+    for i in 0..count {
+        let mut c: u8 = 0;
+        if src.offset(i as usize).copy_in(&mut c as *mut u8, 1).is_err() {
+            return Ok(i)
+        }
+        consputc(c);
+    }
+    Ok(count)
+}
+
+/// User read from the console.
+/// Copy (up to) a whole input line to dst.
+/// Return the number of bytes read.
+pub fn consoleread(mut dst: Address, count: u32) -> Result<u32, &'static str> {
+    let mut cons = CONS.lock();
+    let mut left = count;
+    while left > 0 {
+        // wait until interrupt handler has put some
+        // input into cons.buf.
+        while cons.r == cons.w {
+            let p = unsafe { CPU_MANAGER.my_proc() };
+            if p.interrupted() {
+                drop(cons);
+                return Err("consoleread: interrupted")
+            }
+            let channel = &cons.r as *const usize as usize;
+            p.sleep(channel, cons);
+            cons = CONS.lock();
+        }
+
+        let c = cons.buf[cons.r % INPUT_BUF];
+        cons.r += 1;
+
+        // end-of-file
+        if c == ctrl(b'D') {
+            if left < count {
+                // Save ^D for next time, to make sure
+                // caller gets a 0-byte result.
+                cons.r -= 1;
+            }
+            break
+        }
+
+        // copy the input byte to the user-space buffer.
+        if dst.copy_out(&c as *const u8, 1).is_err() {
+            break
+        }
+        dst = dst.offset(1);
+        left -= 1;
+
+        if c == b'\n' {
+            // a whole line has arrived, return to
+            // the user-level read().
+            break
+        }
+    }
+    drop(cons);
+
+    Ok(count - left)
+}
+
+/// The console input interrupt handler.
+/// uartintr() calls this for input character.
+/// Do erase/kill processing, append to cons.buf,
+/// wake up consoleread() if a whole line has arrived.
+fn consoleintr(c: u8) {
+    let mut cons = CONS.lock();
+
+    match c {
+        // Kill line.
+        c if c == ctrl(b'U') => {
+            while cons.e != cons.w && cons.buf[(cons.e - 1) % INPUT_BUF] != b'\n' {
+                cons.e -= 1;
+                consputbs();
+            }
+        }
+        // Backspace
+        BACKSPACE | DELETE => {
+            if cons.e != cons.w {
+                cons.e -= 1;
+                consputbs();
+            }
+        }
+        _ => {
+            if c != 0 && cons.e - cons.r < INPUT_BUF {
+                let c = if c == b'\r' { b'\n' } else { c };
+
+                // echo back to the user.
+                consputc(c);
+
+                // store for consumption by consoleread().
+                let e = cons.e;
+                cons.buf[e % INPUT_BUF] = c;
+                cons.e += 1;
+
+                if c == b'\n' || c == ctrl(b'D') || cons.e == cons.r + INPUT_BUF {
+                    // wake up consoleread() if a whole line (or end-of-file)
+                    // has arrived.
+                    cons.w = cons.e;
+                    let channel = &cons.r as *const usize as usize;
+                    unsafe { PROC_MANAGER.wakeup(channel); }
+                }
+            }
+        }
+    }
+
+    drop(cons);
+}
@@ -2,6 +2,7 @@ use core::ptr;
 use core::convert::Into;
 
 use crate::consts::UART0;
+use super::consoleintr;
 
 macro_rules! Reg {
     ($reg: expr) => {
@@ -59,3 +60,23 @@ pub fn uartputc(c: u8) {
     while (ReadReg!(LSR) & (1 << 5)) == 0 {}
     WriteReg!(THR, c);
 }
+
+/// Read one input character from the UART.
+/// Return None if none is waiting.
+fn uartgetc() -> Option<u8> {
+    if ReadReg!(LSR) & 0x01 != 0 {
+        // input data is ready.
+        Some(ReadReg!(RHR))
+    } else {
+        None
+    }
+}
+
+/// Handle a uart interrupt, raised because input has arrived.
+/// Called from trap.rs.
+pub fn uartintr() {
+    // read and process incoming characters.
+    while let Some(c) = uartgetc() {
+        consoleintr(c);
+    }
+}
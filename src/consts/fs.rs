@@ -11,6 +11,35 @@ pub const LOGSIZE: usize = MAXOPBLOCKS * 3;
 
 pub const NINODE: usize = 50;
 pub const NDIRECT: usize = 12;
+pub const NINDIRECT: usize = BSIZE / core::mem::size_of::<u32>();
+pub const MAXFILE: usize = NDIRECT + NINDIRECT;
 pub const DIRSIZ: usize = 14;
 pub const ROOTDEV: u32 = 1;
 pub const ROOTINO: u32 = 1;
+
+/// open files per process
+pub const NOFILE: usize = 16;
+/// size of the buffer of a pipe
+pub const PIPESIZE: usize = 512;
+
+/// domain and types for socket
+pub const AF_UNIX: i32 = 1;
+pub const SOCK_STREAM: i32 = 1;
+pub const SOCK_DGRAM: i32 = 2;
+/// maximum connections waiting to be accepted
+pub const SOMAXCONN: usize = 16;
+/// datagrams queued on a socket
+pub const NDGRAM: usize = 16;
+/// maximum size of a datagram
+pub const DGRAMMAX: usize = 1024;
+/// maximum major device number
+pub const NDEV: usize = 10;
+/// major device number of console
+pub const CONSOLE: usize = 1;
+
+/// flags for open
+pub const O_RDONLY: i32 = 0x000;
+pub const O_WRONLY: i32 = 0x001;
+pub const O_RDWR: i32 = 0x002;
+pub const O_CREATE: i32 = 0x200;
+pub const O_TRUNC: i32 = 0x400;
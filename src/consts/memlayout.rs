@@ -57,7 +57,17 @@ pub const TRAMPOLINE: ConstAddr = MAXVA.const_sub(PGSIZE);
 
 /// trapframe is below the trampoline
 /// 0x3FFFFFE000
+/// Each process's trapframe is mapped at its own page, see trapframe(),
+/// so that the threads sharing a page table do not clash.
 pub const TRAPFRAME: ConstAddr = TRAMPOLINE.const_sub(PGSIZE);
 
+/// trapframe of the process at index i of the process table
+pub const fn trapframe(i: usize) -> ConstAddr {
+    TRAPFRAME.const_sub(i * PGSIZE)
+}
+
+/// user memory, including the mapped areas, is below all the trapframes
+pub const USERTOP: ConstAddr = trapframe(NPROC - 1);
+
 /// user text/code start address
 pub const USERTEXT: ConstAddr = ConstAddr(0);
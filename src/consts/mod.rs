@@ -6,6 +6,7 @@ pub use param::*;
 pub use riscv::*;
 
 pub mod fs;
+pub mod signal;
 
 mod memlayout;
 mod param;
@@ -25,6 +26,11 @@ impl ConstAddr {
     pub const fn const_sub(&self, suber: usize) -> Self {
         Self(self.0 - suber)
     }
+
+    /// due to E0015's const restriction
+    pub const fn as_usize(&self) -> usize {
+        self.0
+    }
 }
 
 impl Add for ConstAddr {
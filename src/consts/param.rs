@@ -10,6 +10,20 @@ pub const NSMP: usize = 3;
 
 pub const CONSOLE_BUF: usize = 128;
 
+/// Frequency of mtime in qemu, in Hz.
+pub const MTIME_FREQ: u64 = 10_000_000;
+
+/// Cycles of mtime per clock tick, about 1/10th second in qemu.
+pub const TIMER_INTERVAL: u64 = 1000000;
+
+/// operations for futex
+pub const FUTEX_WAIT: i32 = 0;
+pub const FUTEX_WAKE: i32 = 1;
+
+/// clocks for clock_gettime
+pub const CLOCK_REALTIME: i32 = 0;
+pub const CLOCK_MONOTONIC: i32 = 1;
+
 /// memory design
 pub const PGSIZE: usize = 4096;
 pub const PGSHIFT: usize = 12;
@@ -20,5 +34,35 @@ pub const PGMASKLEN: usize = 9;
 pub const MAXPATH: usize = 128;
 pub const MAXARG: usize = 32;
 
+/// mapped areas per process
+pub const NVMA: usize = 16;
+
+/// prot for mmap
+pub const PROT_NONE: i32 = 0x0;
+pub const PROT_READ: i32 = 0x1;
+pub const PROT_WRITE: i32 = 0x2;
+pub const PROT_EXEC: i32 = 0x4;
+
+/// flags for mmap
+pub const MAP_SHARED: i32 = 0x01;
+pub const MAP_PRIVATE: i32 = 0x02;
+pub const MAP_ANONYMOUS: i32 = 0x20;
+
+/// shared memory segments in the system
+pub const NSHM: usize = 16;
+/// maximum size of a shared memory segment
+pub const SHMMAX: usize = 4 * 1024 * 1024;
+/// key for shmget to always create a new segment
+pub const IPC_PRIVATE: usize = 0;
+/// command for shmctl to remove a segment
+pub const IPC_RMID: i32 = 0;
+
+/// IPC endpoints in the system
+pub const NENDPOINT: usize = 16;
+/// words of an IPC message passed in registers
+pub const MSG_WORDS: usize = 3;
+/// maximum bytes of an IPC message copied from the user buffer
+pub const MSGMAX: usize = 1024;
+
 /// The smallest block size of the buddy system
 pub const LEAF_SIZE: usize = 16;
\ No newline at end of file
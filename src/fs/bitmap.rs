@@ -0,0 +1,58 @@
+//! Block allocation through the free bitmap
+
+use bit_field::BitField;
+
+use core::ptr;
+
+use crate::consts::fs::BSIZE;
+use super::{BCACHE, LOG};
+use super::superblock::{SUPER_BLOCK, BPB};
+
+/// Allocate a zeroed disk block.
+/// Must be called inside a transaction.
+pub fn bm_alloc(dev: u32) -> u32 {
+    let size = unsafe { SUPER_BLOCK.size() };
+    let mut base: u32 = 0;
+    while base < size {
+        let blockno = unsafe { SUPER_BLOCK.bitmap_blockno(base) };
+        let mut buf = BCACHE.bread(dev, blockno);
+        let bits = buf.raw_data_mut() as *mut [u8; BSIZE];
+        let mut offset: u32 = 0;
+        while offset < BPB && base + offset < size {
+            let byte = unsafe { &mut (*bits)[(offset / 8) as usize] };
+            if !byte.get_bit((offset % 8) as usize) {
+                // the block is free, mark it as in use
+                byte.set_bit((offset % 8) as usize, true);
+                LOG.write(buf);
+                bm_zero(dev, base + offset);
+                return base + offset
+            }
+            offset += 1;
+        }
+        drop(buf);
+        base += BPB;
+    }
+    panic!("bitmap: out of blocks");
+}
+
+/// Free a disk block.
+/// Must be called inside a transaction.
+pub fn bm_free(dev: u32, blockno: u32) {
+    let bm_blockno = unsafe { SUPER_BLOCK.bitmap_blockno(blockno) };
+    let mut buf = BCACHE.bread(dev, bm_blockno);
+    let offset = blockno % BPB;
+    let bits = buf.raw_data_mut() as *mut [u8; BSIZE];
+    let byte = unsafe { &mut (*bits)[(offset / 8) as usize] };
+    if !byte.get_bit((offset % 8) as usize) {
+        panic!("bitmap: freeing free block");
+    }
+    byte.set_bit((offset % 8) as usize, false);
+    LOG.write(buf);
+}
+
+/// Zero a block.
+fn bm_zero(dev: u32, blockno: u32) {
+    let mut buf = BCACHE.bread(dev, blockno);
+    unsafe { ptr::write_bytes(buf.raw_data_mut() as *mut u8, 0, BSIZE); }
+    LOG.write(buf);
+}
@@ -1,23 +1,395 @@
+//! Directory-relevant operations
+
+use core::mem;
+
 use crate::consts::fs::{DIRSIZ, ROOTDEV, ROOTINO};
+use crate::mm::Address;
+use crate::process::CPU_MANAGER;
+use super::{ICACHE, Inode, InodeData, InodeType};
+
+/// A directory is a file containing a sequence of DirEntry structures.
+#[repr(C)]
+struct DirEntry {
+    inum: u16,
+    name: [u8; DIRSIZ],
+}
+
+const DIRENT_SIZE: u32 = mem::size_of::<DirEntry>() as u32;
+
+impl DirEntry {
+    const fn empty() -> Self {
+        Self {
+            inum: 0,
+            name: [0; DIRSIZ],
+        }
+    }
+}
+
+impl InodeData {
+    /// Read the directory entry at offset.
+    fn read_dirent(&mut self, offset: u32, de: &mut DirEntry) {
+        let dst = Address::Kernel(de as *mut DirEntry as *const u8);
+        match self.read(dst, offset, DIRENT_SIZE) {
+            Ok(DIRENT_SIZE) => {},
+            _ => panic!("dir: cannot read directory entry"),
+        }
+    }
+
+    /// Look for a directory entry in a directory.
+    /// If found, return the entry's inode and its byte offset.
+    pub fn dir_lookup(&mut self, name: &[u8]) -> Option<(Inode, u32)> {
+        if self.get_itype() != InodeType::Directory {
+            panic!("dir: lookup not in a directory");
+        }
+
+        let mut de = DirEntry::empty();
+        let mut offset = 0;
+        while offset < self.get_size() {
+            self.read_dirent(offset, &mut de);
+            if de.inum != 0 && name_eq(name, &de.name) {
+                return Some((ICACHE.get(self.get_dev(), de.inum as u32), offset))
+            }
+            offset += DIRENT_SIZE;
+        }
+        None
+    }
+
+    /// Write a new directory entry (name, inum) into the directory.
+    /// Must be called inside a transaction.
+    pub fn dir_link(&mut self, name: &[u8], inum: u32) -> Result<(), &'static str> {
+        // check that name is not present
+        if self.dir_lookup(name).is_some() {
+            return Err("dir: name already exists")
+        }
+
+        // look for an empty dirent
+        let mut de = DirEntry::empty();
+        let mut offset = 0;
+        while offset < self.get_size() {
+            self.read_dirent(offset, &mut de);
+            if de.inum == 0 {
+                break
+            }
+            offset += DIRENT_SIZE;
+        }
+
+        de.inum = inum as u16;
+        name_copy(&mut de.name, name);
+        let src = Address::Kernel(&de as *const DirEntry as *const u8);
+        match self.write(src, offset, DIRENT_SIZE) {
+            Ok(DIRENT_SIZE) => Ok(()),
+            _ => panic!("dir: cannot write directory entry"),
+        }
+    }
+
+    /// Clear the directory entry at offset.
+    /// Must be called inside a transaction.
+    fn dir_unlink(&mut self, offset: u32) {
+        let de = DirEntry::empty();
+        let src = Address::Kernel(&de as *const DirEntry as *const u8);
+        match self.write(src, offset, DIRENT_SIZE) {
+            Ok(DIRENT_SIZE) => {},
+            _ => panic!("dir: cannot clear directory entry"),
+        }
+    }
 
-use super::Inode;
-use super::inode::iget;
+    /// Is the directory empty except for "." and ".." ?
+    fn dir_is_empty(&mut self) -> bool {
+        let mut de = DirEntry::empty();
+        let mut offset = 2 * DIRENT_SIZE;
+        while offset < self.get_size() {
+            self.read_dirent(offset, &mut de);
+            if de.inum != 0 {
+                return false
+            }
+            offset += DIRENT_SIZE;
+        }
+        true
+    }
+}
 
-pub fn namei(path: &[u8]) -> &Inode {
+/// Look up and return the inode for a path name.
+/// Must be called inside a transaction since it calls iput.
+pub fn namei(path: &[u8]) -> Option<Inode> {
     let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
     namex(path, false, &mut name)
 }
 
-// TODO
-fn namex(path: &[u8], nameparent: bool, _name: &mut [u8]) -> &'static Inode {
-    if path[0] != b'/' {
-        panic!("namex: path={:?}, not start as root", path);
+/// Return the inode for the parent
+/// and copy the final path element into name.
+/// Must be called inside a transaction since it calls iput.
+pub fn namei_parent(path: &[u8], name: &mut [u8; DIRSIZ]) -> Option<Inode> {
+    namex(path, true, name)
+}
+
+/// Look up and return the inode for a path name.
+/// If is_parent is true, return the inode for the parent and copy the final
+/// path element into name, which must have room for DIRSIZ bytes.
+fn namex(path: &[u8], is_parent: bool, name: &mut [u8; DIRSIZ]) -> Option<Inode> {
+    let mut inode = if path.len() > 0 && path[0] == b'/' {
+        ICACHE.get(ROOTDEV, ROOTINO)
+    } else {
+        let p = unsafe { CPU_MANAGER.my_proc() };
+        unsafe { (*p.data.get()).cwd_dup() }
+    };
+
+    let mut cur: usize = 0;
+    loop {
+        let next = match skip_elem(path, cur, name) {
+            Some(next) => next,
+            None => break,
+        };
+
+        let mut idata = inode.lock();
+        if idata.get_itype() != InodeType::Directory {
+            drop(idata);
+            return None
+        }
+        if is_parent && path_end(path, next) {
+            // stop one level early
+            drop(idata);
+            return Some(inode)
+        }
+        match idata.dir_lookup(name) {
+            Some((next_inode, _)) => {
+                drop(idata);
+                inode = next_inode;
+            }
+            None => {
+                drop(idata);
+                return None
+            }
+        }
+        cur = next;
+    }
+
+    if is_parent {
+        None
+    } else {
+        Some(inode)
+    }
+}
+
+/// Copy the next path element from path into name,
+/// starting to search from index cur.
+/// Return the index following the copied one,
+/// which has no leading slashes,
+/// so the caller can check whether it reaches the end.
+/// If no name to remove, return None.
+///
+/// Examples:
+///   skip_elem("a/bb/c", 0) = Some(2), setting name = "a"
+///   skip_elem("///a//bb", 0) = Some(6), setting name = "a"
+///   skip_elem("a", 0) = Some(1), setting name = "a"
+///   skip_elem("", 0) = skip_elem("////", 0) = None
+fn skip_elem(path: &[u8], mut cur: usize, name: &mut [u8; DIRSIZ]) -> Option<usize> {
+    while !path_end(path, cur) && path[cur] == b'/' {
+        cur += 1;
+    }
+    if path_end(path, cur) {
+        return None
+    }
+
+    let start = cur;
+    while !path_end(path, cur) && path[cur] != b'/' {
+        cur += 1;
+    }
+    let len = cur - start;
+    if len >= DIRSIZ {
+        name.copy_from_slice(&path[start..start+DIRSIZ]);
+    } else {
+        name[..len].copy_from_slice(&path[start..cur]);
+        name[len] = 0;
+    }
+
+    while !path_end(path, cur) && path[cur] == b'/' {
+        cur += 1;
+    }
+    Some(cur)
+}
+
+#[inline]
+fn path_end(path: &[u8], cur: usize) -> bool {
+    cur >= path.len() || path[cur] == 0
+}
+
+/// Compare two directory names, which are at most DIRSIZ long
+/// and terminated by zero if shorter.
+fn name_eq(a: &[u8], b: &[u8]) -> bool {
+    for i in 0..DIRSIZ {
+        let ca = if i < a.len() { a[i] } else { 0 };
+        let cb = if i < b.len() { b[i] } else { 0 };
+        if ca != cb {
+            return false
+        }
+        if ca == 0 {
+            break
+        }
+    }
+    true
+}
+
+/// Copy name into a directory entry's name field.
+fn name_copy(dst: &mut [u8; DIRSIZ], src: &[u8]) {
+    let mut i = 0;
+    while i < DIRSIZ && i < src.len() && src[i] != 0 {
+        dst[i] = src[i];
+        i += 1;
+    }
+    for c in dst[i..].iter_mut() {
+        *c = 0;
+    }
+}
+
+/// Create a new inode at path with type itype.
+/// If the path already exists and is a file or device being opened,
+/// return the existing inode.
+/// Must be called inside a transaction.
+pub fn create(path: &[u8], itype: InodeType, major: u16, minor: u16)
+    -> Result<Inode, &'static str>
+{
+    let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
+    let dir_inode = namei_parent(path, &mut name).ok_or("create: parent not found")?;
+    let mut dir_idata = dir_inode.lock();
+
+    if let Some((inode, _)) = dir_idata.dir_lookup(&name) {
+        drop(dir_idata);
+        let idata = inode.lock();
+        if itype == InodeType::File &&
+            (idata.get_itype() == InodeType::File || idata.get_itype() == InodeType::Device)
+        {
+            drop(idata);
+            return Ok(inode)
+        }
+        drop(idata);
+        return Err("create: path already exists")
+    }
+
+    let inode = match ICACHE.alloc(dir_idata.get_dev(), itype) {
+        Some(inode) => inode,
+        None => {
+            drop(dir_idata);
+            return Err("create: no free inode")
+        }
+    };
+    let mut idata = inode.lock();
+    idata.set_device(major, minor);
+    idata.set_nlink(1);
+    idata.update();
+
+    if itype == InodeType::Directory {
+        // create . and .. entries
+        // no nlink++ for ".": avoid cyclic ref count
+        let inum = idata.get_inum();
+        idata.dir_link(b".", inum).expect("create: link .");
+        idata.dir_link(b"..", dir_idata.get_inum()).expect("create: link ..");
+    }
+
+    if let Err(err) = dir_idata.dir_link(&name, idata.get_inum()) {
+        // something went wrong, de-allocate the new inode
+        idata.set_nlink(0);
+        idata.update();
+        drop(idata);
+        drop(dir_idata);
+        return Err(err)
+    }
+
+    if itype == InodeType::Directory {
+        // now that success is guaranteed:
+        // for the new directory's ".."
+        let nlink = dir_idata.get_nlink();
+        dir_idata.set_nlink(nlink + 1);
+        dir_idata.update();
+    }
+
+    drop(idata);
+    drop(dir_idata);
+    Ok(inode)
+}
+
+/// Create the path new as a link to the same inode as old.
+/// Must be called inside a transaction.
+pub fn link(old: &[u8], new: &[u8]) -> Result<(), &'static str> {
+    let inode = namei(old).ok_or("link: old path not found")?;
+    let mut idata = inode.lock();
+    if idata.get_itype() == InodeType::Directory {
+        drop(idata);
+        return Err("link: old path is a directory")
     }
-    if nameparent {
-        panic!("namex: nameparent not supported yet");
+    let nlink = idata.get_nlink();
+    idata.set_nlink(nlink + 1);
+    idata.update();
+    let dev = idata.get_dev();
+    let inum = idata.get_inum();
+    drop(idata);
+
+    let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
+    let res = match namei_parent(new, &mut name) {
+        Some(dir_inode) => {
+            let mut dir_idata = dir_inode.lock();
+            let res = if dir_idata.get_dev() != dev {
+                Err("link: cross device")
+            } else {
+                dir_idata.dir_link(&name, inum)
+            };
+            drop(dir_idata);
+            res
+        }
+        None => Err("link: new path's parent not found"),
+    };
+
+    if res.is_err() {
+        let mut idata = inode.lock();
+        let nlink = idata.get_nlink();
+        idata.set_nlink(nlink - 1);
+        idata.update();
+        drop(idata);
     }
+    res
+}
+
+/// Remove the directory entry at path.
+/// Must be called inside a transaction.
+pub fn unlink(path: &[u8]) -> Result<(), &'static str> {
+    let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
+    let dir_inode = namei_parent(path, &mut name).ok_or("unlink: parent not found")?;
+    let mut dir_idata = dir_inode.lock();
 
-    let ip = iget(ROOTDEV, ROOTINO);
-    
-    ip
-}
\ No newline at end of file
+    // cannot unlink "." or ".."
+    if name_eq(&name, b".") || name_eq(&name, b"..") {
+        drop(dir_idata);
+        return Err("unlink: cannot unlink . or ..")
+    }
+
+    let (inode, offset) = match dir_idata.dir_lookup(&name) {
+        Some(found) => found,
+        None => {
+            drop(dir_idata);
+            return Err("unlink: path not found")
+        }
+    };
+    let mut idata = inode.lock();
+
+    if idata.get_nlink() < 1 {
+        panic!("unlink: nlink < 1");
+    }
+    if idata.get_itype() == InodeType::Directory && !idata.dir_is_empty() {
+        drop(idata);
+        drop(dir_idata);
+        return Err("unlink: directory not empty")
+    }
+
+    dir_idata.dir_unlink(offset);
+    if idata.get_itype() == InodeType::Directory {
+        let nlink = dir_idata.get_nlink();
+        dir_idata.set_nlink(nlink - 1);
+        dir_idata.update();
+    }
+    drop(dir_idata);
+
+    let nlink = idata.get_nlink();
+    idata.set_nlink(nlink - 1);
+    idata.update();
+    drop(idata);
+    Ok(())
+}
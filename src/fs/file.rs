@@ -0,0 +1,372 @@
+//! File descriptor layer
+
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::cmp;
+use core::mem::ManuallyDrop;
+
+use crate::consts::fs::{BSIZE, MAXOPBLOCKS, NDEV, CONSOLE, O_CREATE, O_RDWR, O_WRONLY, O_TRUNC};
+use crate::console;
+use crate::mm::Address;
+use crate::process::Endpoint;
+use super::{LOG, Inode, InodeType, FileStat, create, namei};
+use super::pipe::Pipe;
+use super::socket::Socket;
+
+/// Maximum bytes to write in one transaction,
+/// including i-node, indirect block, allocation blocks,
+/// and 2 blocks of slop for non-aligned writes.
+const MAX_WRITE: u32 = (((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE) as u32;
+
+/// An open file, shared between file descriptors through Arc.
+/// Dropping the last reference closes the file.
+pub struct File {
+    inner: FileInner,
+    readable: bool,
+    writable: bool,
+}
+
+// The offset of an inode file is guarded by the inode's sleeplock.
+unsafe impl Send for File {}
+unsafe impl Sync for File {}
+
+enum FileInner {
+    Inode(FileInode),
+    Device(FileDevice),
+    Pipe(Arc<Pipe>),
+    Endpoint(Endpoint),
+    Socket(Arc<Socket>),
+}
+
+struct FileInode {
+    inode: ManuallyDrop<Inode>,
+    offset: UnsafeCell<u32>,
+}
+
+struct FileDevice {
+    inode: ManuallyDrop<Inode>,
+    major: u16,
+}
+
+impl File {
+    /// Open the file at path according to flags.
+    pub fn open(path: &[u8], flags: i32) -> Result<Arc<Self>, &'static str> {
+        LOG.begin_op();
+        let res = Self::open_inode(path, flags);
+        LOG.end_op();
+        res
+    }
+
+    /// Must be called inside a transaction.
+    fn open_inode(path: &[u8], flags: i32) -> Result<Arc<Self>, &'static str> {
+        let inode = if flags & O_CREATE > 0 {
+            create(path, InodeType::File, 0, 0)?
+        } else {
+            namei(path).ok_or("open: path not found")?
+        };
+
+        let mut idata = inode.lock();
+        let itype = idata.get_itype();
+        let major = idata.get_major();
+        match itype {
+            InodeType::Directory => {
+                if flags != 0 {
+                    drop(idata);
+                    return Err("open: directory can only be opened read-only")
+                }
+            }
+            InodeType::Device => {
+                if major as usize >= NDEV {
+                    drop(idata);
+                    return Err("open: invalid major device number")
+                }
+            }
+            InodeType::File => {
+                if flags & O_TRUNC > 0 {
+                    idata.truncate();
+                }
+            }
+            InodeType::Socket => {
+                drop(idata);
+                return Err("open: cannot open a socket")
+            }
+            InodeType::Empty => panic!("open: empty inode"),
+        }
+        drop(idata);
+
+        let inner = if itype == InodeType::Device {
+            FileInner::Device(FileDevice {
+                inode: ManuallyDrop::new(inode),
+                major,
+            })
+        } else {
+            FileInner::Inode(FileInode {
+                inode: ManuallyDrop::new(inode),
+                offset: UnsafeCell::new(0),
+            })
+        };
+
+        Ok(Arc::new(File {
+            inner,
+            readable: flags & O_WRONLY == 0,
+            writable: flags & O_WRONLY > 0 || flags & O_RDWR > 0,
+        }))
+    }
+
+    /// Create a pipe, and return its read end and write end.
+    pub fn pipe() -> Result<(Arc<Self>, Arc<Self>), &'static str> {
+        let pipe = Arc::new(Pipe::new());
+        let read_end = Arc::new(File {
+            inner: FileInner::Pipe(Arc::clone(&pipe)),
+            readable: true,
+            writable: false,
+        });
+        let write_end = Arc::new(File {
+            inner: FileInner::Pipe(pipe),
+            readable: false,
+            writable: true,
+        });
+        Ok((read_end, write_end))
+    }
+
+    /// Create an IPC endpoint, which can only be used by the IPC syscalls.
+    pub fn endpoint() -> Result<Arc<Self>, &'static str> {
+        Ok(Arc::new(File {
+            inner: FileInner::Endpoint(Endpoint::new()?),
+            readable: false,
+            writable: false,
+        }))
+    }
+
+    /// Create a Unix domain socket of type stype.
+    pub fn socket(stype: i32) -> Result<Arc<Self>, &'static str> {
+        Ok(Self::from_socket(Socket::new(stype)?))
+    }
+
+    /// Accept a connection on the listening socket,
+    /// and return the new socket connected to it.
+    pub fn accept(&self) -> Result<Arc<Self>, &'static str> {
+        Ok(Self::from_socket(self.as_socket()?.accept()?))
+    }
+
+    fn from_socket(sock: Socket) -> Arc<Self> {
+        Arc::new(File {
+            inner: FileInner::Socket(Arc::new(sock)),
+            readable: true,
+            writable: true,
+        })
+    }
+
+    /// Return the socket if the file is one.
+    pub fn as_socket(&self) -> Result<&Arc<Socket>, &'static str> {
+        match &self.inner {
+            FileInner::Socket(sock) => Ok(sock),
+            _ => Err("file: not a socket"),
+        }
+    }
+
+    /// Return the IPC endpoint if the file is one.
+    pub fn as_endpoint(&self) -> Result<&Endpoint, &'static str> {
+        match &self.inner {
+            FileInner::Endpoint(ep) => Ok(ep),
+            _ => Err("file: not an endpoint"),
+        }
+    }
+
+    /// Read count bytes from the file to user virtual address addr.
+    /// Return the number of bytes read.
+    pub fn read(&self, addr: usize, count: u32) -> Result<u32, &'static str> {
+        if !self.readable {
+            return Err("file: not readable")
+        }
+
+        match &self.inner {
+            FileInner::Inode(fi) => {
+                let mut idata = fi.inode.lock();
+                let offset = unsafe { &mut *fi.offset.get() };
+                let res = idata.read(Address::Virtual(addr), *offset, count);
+                if let Ok(n) = res {
+                    *offset += n;
+                }
+                drop(idata);
+                res
+            }
+            FileInner::Device(fd) => {
+                match fd.major as usize {
+                    CONSOLE => console::consoleread(Address::Virtual(addr), count),
+                    _ => Err("file: no such device"),
+                }
+            }
+            FileInner::Pipe(pipe) => pipe.read(addr, count),
+            FileInner::Endpoint(_) => Err("file: cannot read an endpoint"),
+            FileInner::Socket(sock) => sock.recv(addr, count),
+        }
+    }
+
+    /// Write count bytes from user virtual address addr to the file.
+    /// Return the number of bytes written.
+    pub fn write(&self, addr: usize, count: u32) -> Result<u32, &'static str> {
+        if !self.writable {
+            return Err("file: not writable")
+        }
+
+        match &self.inner {
+            FileInner::Inode(fi) => {
+                // write a few blocks at a time to avoid exceeding
+                // the maximum log transaction size.
+                let mut i: u32 = 0;
+                while i < count {
+                    let n = cmp::min(count - i, MAX_WRITE);
+                    LOG.begin_op();
+                    let mut idata = fi.inode.lock();
+                    let offset = unsafe { &mut *fi.offset.get() };
+                    let res = idata.write(Address::Virtual(addr + i as usize), *offset, n);
+                    if let Ok(written) = res {
+                        *offset += written;
+                    }
+                    drop(idata);
+                    LOG.end_op();
+
+                    if res? != n {
+                        return Err("file: short write")
+                    }
+                    i += n;
+                }
+                Ok(count)
+            }
+            FileInner::Device(fd) => {
+                match fd.major as usize {
+                    CONSOLE => console::consolewrite(Address::Virtual(addr), count),
+                    _ => Err("file: no such device"),
+                }
+            }
+            FileInner::Pipe(pipe) => pipe.write(addr, count),
+            FileInner::Endpoint(_) => Err("file: cannot write an endpoint"),
+            FileInner::Socket(sock) => sock.send(addr, count, None),
+        }
+    }
+
+    /// Check if the file can be mapped by mmap.
+    /// Only readable inode files can be mapped,
+    /// and a shared writable mapping also needs the file to be writable.
+    pub fn mappable(&self, shared_write: bool) -> Result<(), &'static str> {
+        match &self.inner {
+            FileInner::Inode(_) => {},
+            FileInner::Device(_) => return Err("file: cannot map a device"),
+            FileInner::Pipe(_) => return Err("file: cannot map a pipe"),
+            FileInner::Endpoint(_) => return Err("file: cannot map an endpoint"),
+            FileInner::Socket(_) => return Err("file: cannot map a socket"),
+        }
+        if !self.readable {
+            return Err("file: not readable")
+        }
+        if shared_write && !self.writable {
+            return Err("file: not writable")
+        }
+        Ok(())
+    }
+
+    /// The device and inode number of an inode file,
+    /// which identify it among the open files.
+    pub fn inode_key(&self) -> Option<(u32, u32)> {
+        match &self.inner {
+            FileInner::Inode(fi) => Some((fi.inode.dev(), fi.inode.inum())),
+            _ => None,
+        }
+    }
+
+    /// Read up to count bytes at offset to dst,
+    /// without moving the file offset.
+    /// Used to fill in mapped pages.
+    /// Return the number of bytes read, which is short at the end of file.
+    pub fn read_at(&self, dst: Address, offset: u32, count: u32) -> Result<u32, &'static str> {
+        match &self.inner {
+            FileInner::Inode(fi) => {
+                let mut idata = fi.inode.lock();
+                let res = if offset >= idata.get_size() {
+                    Ok(0)
+                } else {
+                    idata.read(dst, offset, count)
+                };
+                drop(idata);
+                res
+            }
+            _ => Err("file: cannot read at offset"),
+        }
+    }
+
+    /// Write up to count bytes from src at offset,
+    /// without moving the file offset or growing the file.
+    /// Used to write back mapped pages.
+    /// Return the number of bytes written.
+    pub fn write_at(&self, src: Address, offset: u32, count: u32) -> Result<u32, &'static str> {
+        let fi = match &self.inner {
+            FileInner::Inode(fi) => fi,
+            _ => return Err("file: cannot write at offset"),
+        };
+
+        let mut i: u32 = 0;
+        while i < count {
+            LOG.begin_op();
+            let mut idata = fi.inode.lock();
+            let size = idata.get_size();
+            let res = if offset + i >= size {
+                Ok(0)
+            } else {
+                let n = cmp::min(cmp::min(count - i, size - offset - i), MAX_WRITE);
+                idata.write(src.offset(i as usize), offset + i, n)
+            };
+            drop(idata);
+            LOG.end_op();
+
+            match res? {
+                0 => break,
+                n => i += n,
+            }
+        }
+        Ok(i)
+    }
+
+    /// Copy the file's metadata to user virtual address addr.
+    pub fn stat(&self, addr: usize) -> Result<(), &'static str> {
+        let inode = match &self.inner {
+            FileInner::Inode(fi) => &fi.inode,
+            FileInner::Device(fd) => &fd.inode,
+            FileInner::Pipe(_) => return Err("file: cannot stat a pipe"),
+            FileInner::Endpoint(_) => return Err("file: cannot stat an endpoint"),
+            FileInner::Socket(_) => return Err("file: cannot stat a socket"),
+        };
+
+        let mut st = FileStat::uninit();
+        let idata = inode.lock();
+        idata.stat(&mut st);
+        drop(idata);
+        Address::Virtual(addr).copy_out(
+            &st as *const FileStat as *const u8,
+            core::mem::size_of::<FileStat>(),
+        )
+    }
+}
+
+impl Drop for File {
+    /// Close the file.
+    /// The inode must be put inside a transaction.
+    fn drop(&mut self) {
+        let inode = match &mut self.inner {
+            FileInner::Inode(fi) => &mut fi.inode,
+            FileInner::Device(fd) => &mut fd.inode,
+            FileInner::Pipe(pipe) => {
+                pipe.close(self.writable);
+                return
+            }
+            FileInner::Socket(sock) => {
+                sock.close();
+                return
+            }
+            FileInner::Endpoint(_) => return,
+        };
+        LOG.begin_op();
+        unsafe { ManuallyDrop::drop(inode); }
+        LOG.end_op();
+    }
+}
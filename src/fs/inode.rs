@@ -2,69 +2,503 @@
 
 use array_macro::array;
 
+use core::mem;
+use core::ptr;
+
+use crate::consts::fs::{NINODE, NDIRECT, NINDIRECT, MAXFILE, BSIZE};
+use crate::mm::Address;
+use crate::sleeplock::{SleepLock, SleepLockGuard};
 use crate::spinlock::SpinLock;
-use crate::consts::fs::NINODE;
-use super::Inode;
+use super::{BCACHE, LOG};
+use super::bitmap::{bm_alloc, bm_free};
+use super::superblock::SUPER_BLOCK;
+
+/// Inodes per block
+pub const IPB: usize = BSIZE / mem::size_of::<DiskInode>();
 
-static mut ICACHE: Icache = Icache::new();
+pub static ICACHE: InodeCache = InodeCache::new();
 
-struct Icache {
-    lock: SpinLock<()>,
-    inodes: [Inode; NINODE],
+/// In-memory copies of inodes.
+/// The meta part records which inode each slot holds and its reference count,
+/// guarded by a spinlock; the data part is the copy of disk inode,
+/// guarded by its own sleeplock.
+pub struct InodeCache {
+    meta: SpinLock<[InodeMeta; NINODE]>,
+    data: [SleepLock<InodeData>; NINODE],
 }
 
-impl Icache {
+impl InodeCache {
     const fn new() -> Self {
         Self {
-            lock: SpinLock::new((), "icache"),
-            inodes: array![_ => Inode::new(); NINODE],
+            meta: SpinLock::new(array![_ => InodeMeta::new(); NINODE], "InodeMeta"),
+            data: array![_ => SleepLock::new(InodeData::new(), "InodeData"); NINODE],
+        }
+    }
+
+    /// Find the inode with number inum on device dev
+    /// and return the in-memory copy. Does not lock
+    /// the inode and does not read it from disk.
+    pub fn get(&self, dev: u32, inum: u32) -> Inode {
+        let mut guard = self.meta.lock();
+
+        // Is the inode we are looking for already cached?
+        let mut empty_i: Option<usize> = None;
+        for i in 0..NINODE {
+            if guard[i].refs > 0 && guard[i].dev == dev && guard[i].inum == inum {
+                guard[i].refs += 1;
+                drop(guard);
+                return Inode { dev, inum, index: i }
+            }
+            if empty_i.is_none() && guard[i].refs == 0 {
+                empty_i = Some(i);
+            }
+        }
+
+        // Recycle an inode cache entry
+        let empty_i = match empty_i {
+            Some(i) => i,
+            None => panic!("inode: not enough space in inode cache"),
+        };
+        guard[empty_i].dev = dev;
+        guard[empty_i].inum = inum;
+        guard[empty_i].refs = 1;
+        drop(guard);
+        Inode { dev, inum, index: empty_i }
+    }
+
+    /// Increment the reference count of the inode.
+    fn dup(&self, inode: &Inode) -> Inode {
+        let mut guard = self.meta.lock();
+        guard[inode.index].refs += 1;
+        drop(guard);
+        Inode {
+            dev: inode.dev,
+            inum: inode.inum,
+            index: inode.index,
+        }
+    }
+
+    /// Drop a reference to an in-memory inode.
+    /// If that was the last reference, the inode cache entry can be recycled.
+    /// If that was the last reference and the inode has no links to it,
+    /// free the inode (and its content) on disk.
+    /// All calls to it must be inside a transaction,
+    /// in case it has to free the inode.
+    fn put(&self, inode: &mut Inode) {
+        let i = inode.index;
+        let mut guard = self.meta.lock();
+
+        if guard[i].refs == 1 {
+            // refs == 1 means no other process can have the inode locked,
+            // so this sleeplock won't block (or deadlock).
+            let mut idata = self.data[i].lock();
+            if idata.valid && idata.dinode.nlink == 0 {
+                // inode has no links and no other references:
+                // truncate and free.
+                drop(guard);
+                idata.truncate();
+                idata.dinode.itype = InodeType::Empty;
+                idata.update();
+                idata.valid = false;
+                drop(idata);
+                guard = self.meta.lock();
+            } else {
+                drop(idata);
+            }
         }
+
+        guard[i].refs -= 1;
+        drop(guard);
+    }
+
+    /// Allocate an inode on device dev.
+    /// Mark it as allocated by giving it type itype.
+    /// Return an unlocked but allocated and referenced inode.
+    /// Must be called inside a transaction.
+    pub fn alloc(&self, dev: u32, itype: InodeType) -> Option<Inode> {
+        let ninodes = unsafe { SUPER_BLOCK.inode_size() };
+        for inum in 1..ninodes {
+            let blockno = unsafe { SUPER_BLOCK.locate_inode(inum) };
+            let offset = locate_inode_offset(inum);
+            let mut buf = BCACHE.bread(dev, blockno);
+            let dinode = unsafe { (buf.raw_data_mut() as *mut DiskInode).add(offset) };
+            let dinode = unsafe { &mut *dinode };
+            if dinode.itype == InodeType::Empty {
+                // a free inode
+                unsafe { ptr::write_bytes(dinode as *mut DiskInode, 0, 1); }
+                dinode.itype = itype;
+                // mark it allocated on the disk
+                LOG.write(buf);
+                return Some(self.get(dev, inum))
+            }
+            drop(buf);
+        }
+        println!("inode: no free inodes");
+        None
     }
 }
 
-/// Find the inode with number inum on device dev
-/// and return the in-memory copy. Does not lock
-/// the inode and does not read it from disk.
-pub fn iget(dev: u32, inum: u32) -> &'static Inode {
-    let icache = unsafe {ICACHE.lock.lock()};
+/// Handle to an inode cached in ICACHE.
+/// Cloning it increments the reference count,
+/// dropping it decrements the reference count
+/// and it must be dropped inside a transaction.
+#[derive(Debug)]
+pub struct Inode {
+    dev: u32,
+    inum: u32,
+    index: usize,
+}
+
+impl Inode {
+    /// Lock the inode.
+    /// Read the inode from disk if necessary.
+    pub fn lock(&self) -> SleepLockGuard<'_, InodeData> {
+        let mut guard = ICACHE.data[self.index].lock();
 
-    // Is the inode we are looking for already cached?
-    let mut empty: Option<&mut Inode> = None;
-    for ip in unsafe {ICACHE.inodes.iter_mut()} {
-        if ip.iref > 0 && ip.dev == dev && ip.inum == inum {
-            ip.iref += 1;
-            drop(icache);
-            return ip;
+        if !guard.valid {
+            let blockno = unsafe { SUPER_BLOCK.locate_inode(self.inum) };
+            let offset = locate_inode_offset(self.inum);
+            let buf = BCACHE.bread(self.dev, blockno);
+            unsafe {
+                let dinode = (buf.raw_data() as *const DiskInode).add(offset);
+                ptr::copy_nonoverlapping(dinode, &mut guard.dinode, 1);
+            }
+            drop(buf);
+            guard.valid = true;
+            guard.dev = self.dev;
+            guard.inum = self.inum;
+            if guard.dinode.itype == InodeType::Empty {
+                panic!("inode: lock an empty inode");
+            }
         }
-        if empty.is_none() && ip.iref == 0 {
-            empty = Some(ip);
+
+        guard
+    }
+
+    pub fn inum(&self) -> u32 {
+        self.inum
+    }
+
+    pub fn dev(&self) -> u32 {
+        self.dev
+    }
+}
+
+impl Clone for Inode {
+    fn clone(&self) -> Self {
+        ICACHE.dup(self)
+    }
+}
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        ICACHE.put(self);
+    }
+}
+
+struct InodeMeta {
+    dev: u32,
+    inum: u32,
+    refs: usize,
+}
+
+impl InodeMeta {
+    const fn new() -> Self {
+        Self {
+            dev: 0,
+            inum: 0,
+            refs: 0,
         }
     }
+}
 
-    // Recycle an inode cacahe entry
-    if empty.is_none() {
-        panic!("iget: no enough space in inode cache");
+/// In-memory copy of an inode's content.
+/// Guarded by the inode's sleeplock.
+pub struct InodeData {
+    valid: bool,
+    dev: u32,
+    inum: u32,
+    dinode: DiskInode,
+}
+
+impl InodeData {
+    const fn new() -> Self {
+        Self {
+            valid: false,
+            dev: 0,
+            inum: 0,
+            dinode: DiskInode::new(),
+        }
+    }
+
+    #[inline]
+    pub fn get_itype(&self) -> InodeType {
+        self.dinode.itype
+    }
+
+    #[inline]
+    pub fn get_major(&self) -> u16 {
+        self.dinode.major
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> u32 {
+        self.dinode.size
+    }
+
+    #[inline]
+    pub fn get_nlink(&self) -> u16 {
+        self.dinode.nlink
+    }
+
+    #[inline]
+    pub fn get_dev(&self) -> u32 {
+        self.dev
+    }
+
+    #[inline]
+    pub fn get_inum(&self) -> u32 {
+        self.inum
+    }
+
+    /// Set the device number of a newly created device inode.
+    pub fn set_device(&mut self, major: u16, minor: u16) {
+        self.dinode.major = major;
+        self.dinode.minor = minor;
+    }
+
+    pub fn set_nlink(&mut self, nlink: u16) {
+        self.dinode.nlink = nlink;
+    }
+
+    /// Copy a modified in-memory inode to disk.
+    /// Must be called after every change to an InodeData field
+    /// that lives on disk.
+    /// Must be called inside a transaction.
+    pub fn update(&mut self) {
+        let blockno = unsafe { SUPER_BLOCK.locate_inode(self.inum) };
+        let offset = locate_inode_offset(self.inum);
+        let mut buf = BCACHE.bread(self.dev, blockno);
+        unsafe {
+            let dinode = (buf.raw_data_mut() as *mut DiskInode).add(offset);
+            ptr::copy_nonoverlapping(&self.dinode, dinode, 1);
+        }
+        LOG.write(buf);
+    }
+
+    /// Truncate the inode (discard contents).
+    /// Must be called inside a transaction.
+    pub fn truncate(&mut self) {
+        let dev = self.dev;
+
+        for i in 0..NDIRECT {
+            if self.dinode.addrs[i] > 0 {
+                bm_free(dev, self.dinode.addrs[i]);
+                self.dinode.addrs[i] = 0;
+            }
+        }
+
+        if self.dinode.addrs[NDIRECT] > 0 {
+            let buf = BCACHE.bread(dev, self.dinode.addrs[NDIRECT]);
+            let blocknos = buf.raw_data() as *const [u32; NINDIRECT];
+            for &blockno in unsafe { (*blocknos).iter() } {
+                if blockno > 0 {
+                    bm_free(dev, blockno);
+                }
+            }
+            drop(buf);
+            bm_free(dev, self.dinode.addrs[NDIRECT]);
+            self.dinode.addrs[NDIRECT] = 0;
+        }
+
+        self.dinode.size = 0;
+        self.update();
+    }
+
+    /// Copy stat information from inode.
+    pub fn stat(&self, st: &mut FileStat) {
+        st.dev = self.dev;
+        st.inum = self.inum;
+        st.itype = self.dinode.itype;
+        st.nlink = self.dinode.nlink;
+        st.size = self.dinode.size as u64;
+    }
+
+    /// Return the disk block address of the nth block in the inode.
+    /// If there is no such block, allocate one.
+    /// Must be called inside a transaction if it might allocate.
+    fn map_blockno(&mut self, offset_bn: usize) -> u32 {
+        if offset_bn < NDIRECT {
+            if self.dinode.addrs[offset_bn] == 0 {
+                self.dinode.addrs[offset_bn] = bm_alloc(self.dev);
+            }
+            return self.dinode.addrs[offset_bn]
+        }
+
+        let offset_bn = offset_bn - NDIRECT;
+        if offset_bn >= NINDIRECT {
+            panic!("inode: queried block number out of range");
+        }
+
+        // load indirect block, allocating if necessary
+        if self.dinode.addrs[NDIRECT] == 0 {
+            self.dinode.addrs[NDIRECT] = bm_alloc(self.dev);
+        }
+        let mut buf = BCACHE.bread(self.dev, self.dinode.addrs[NDIRECT]);
+        let blockno_ptr = unsafe { (buf.raw_data_mut() as *mut u32).add(offset_bn) };
+        let mut blockno = unsafe { ptr::read(blockno_ptr) };
+        if blockno == 0 {
+            blockno = bm_alloc(self.dev);
+            unsafe { ptr::write(blockno_ptr, blockno); }
+            LOG.write(buf);
+        } else {
+            drop(buf);
+        }
+        blockno
+    }
+
+    /// Read data from inode into dst.
+    /// Return the number of bytes read,
+    /// which is smaller than count if it reaches the end of the file.
+    pub fn read(&mut self, mut dst: Address, offset: u32, count: u32)
+        -> Result<u32, &'static str>
+    {
+        let size = self.dinode.size;
+        if offset > size || offset.checked_add(count).is_none() {
+            return Ok(0)
+        }
+        let count = if offset + count > size { size - offset } else { count };
+
+        let mut tot: u32 = 0;
+        let mut offset = offset;
+        while tot < count {
+            let blockno = self.map_blockno(offset as usize / BSIZE);
+            let buf = BCACHE.bread(self.dev, blockno);
+            let block_offset = offset as usize % BSIZE;
+            let n = core::cmp::min((count - tot) as usize, BSIZE - block_offset);
+            let src = unsafe { (buf.raw_data() as *const u8).add(block_offset) };
+            let res = dst.copy_out(src, n);
+            drop(buf);
+            res?;
+            tot += n as u32;
+            offset += n as u32;
+            dst = dst.offset(n);
+        }
+
+        Ok(tot)
+    }
+
+    /// Write data from src to the inode.
+    /// Return the number of bytes written.
+    /// Must be called inside a transaction.
+    pub fn write(&mut self, mut src: Address, offset: u32, count: u32)
+        -> Result<u32, &'static str>
+    {
+        if offset > self.dinode.size {
+            return Err("inode write: offset bigger than file size")
+        }
+        let end = match offset.checked_add(count) {
+            Some(end) => end,
+            None => return Err("inode write: offset overflow"),
+        };
+        if end as usize > MAXFILE * BSIZE {
+            return Err("inode write: file too large")
+        }
+
+        let mut tot: u32 = 0;
+        let mut offset = offset;
+        let mut res = Ok(());
+        while tot < count {
+            let blockno = self.map_blockno(offset as usize / BSIZE);
+            let mut buf = BCACHE.bread(self.dev, blockno);
+            let block_offset = offset as usize % BSIZE;
+            let n = core::cmp::min((count - tot) as usize, BSIZE - block_offset);
+            let dst = unsafe { (buf.raw_data_mut() as *mut u8).add(block_offset) };
+            res = src.copy_in(dst, n);
+            if res.is_err() {
+                drop(buf);
+                break;
+            }
+            LOG.write(buf);
+            tot += n as u32;
+            offset += n as u32;
+            src = src.offset(n);
+        }
+
+        if offset > self.dinode.size {
+            self.dinode.size = offset;
+        }
+
+        // write the inode back to disk even if the size didn't change
+        // because the loop above might have allocated a new block
+        // and added it to addrs
+        self.update();
+
+        res.map(|_| tot)
     }
-    let ip: &mut Inode = empty.take().unwrap();
-    ip.dev = dev;
-    ip.inum = inum;
-    ip.iref = 1;
-    ip.valid = false;
-    drop(icache);
-    ip
 }
 
-/// Lock the given inode.
-/// Reads the inode from disk if necessary.
-/// LTODO - do not lock the inode yet, only process zero exists
-pub fn ilock(ip: &mut Inode) {
-    if ip.iref < 1 {
-        panic!("ilock: iref smaller than 1");
+/// Offset index of inode inum inside its block.
+#[inline]
+fn locate_inode_offset(inum: u32) -> usize {
+    inum as usize % IPB
+}
+
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InodeType {
+    Empty = 0,
+    Directory = 1,
+    File = 2,
+    Device = 3,
+    Socket = 4,
+}
+
+/// On-disk inode structure
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DiskInode {
+    itype: InodeType,           // File type
+    major: u16,                 // Major device number (Device only)
+    minor: u16,                 // Minor device number (Device only)
+    nlink: u16,                 // Number of links to inode in file system
+    size: u32,                  // Size of file (bytes)
+    addrs: [u32; NDIRECT + 1],  // Data block addresses
+}
+
+impl DiskInode {
+    const fn new() -> Self {
+        Self {
+            itype: InodeType::Empty,
+            major: 0,
+            minor: 0,
+            nlink: 0,
+            size: 0,
+            addrs: [0; NDIRECT + 1],
+        }
     }
+}
 
-    // acquire sleep lock
+/// File status handed out to the user by fstat.
+/// Keep the same layout as struct stat in xv6-riscv.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FileStat {
+    dev: u32,
+    inum: u32,
+    itype: InodeType,
+    nlink: u16,
+    size: u64,
+}
 
-    if !ip.valid {
-        // bp = bread(ip.dev, )
+impl FileStat {
+    pub const fn uninit() -> Self {
+        Self {
+            dev: 0,
+            inum: 0,
+            itype: InodeType::Empty,
+            nlink: 0,
+            size: 0,
+        }
     }
 }
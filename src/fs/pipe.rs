@@ -0,0 +1,131 @@
+//! Pipes
+
+use core::cmp;
+
+use crate::consts::fs::PIPESIZE;
+use crate::consts::signal::SIGPIPE;
+use crate::mm::Address;
+use crate::process::{CPU_MANAGER, PROC_MANAGER};
+use crate::spinlock::SpinLock;
+
+/// A ring buffer shared by the read end and the write end of a pipe.
+/// The user data is copied through a buffer on the kernel stack,
+/// since copying to or from user space may sleep on the user space lock.
+pub struct Pipe(SpinLock<PipeInner>);
+
+struct PipeInner {
+    data: [u8; PIPESIZE],
+    /// number of bytes read
+    nread: usize,
+    /// number of bytes written
+    nwrite: usize,
+    /// read end is still open
+    readopen: bool,
+    /// write end is still open
+    writeopen: bool,
+}
+
+impl Pipe {
+    pub const fn new() -> Self {
+        Self(SpinLock::new(PipeInner {
+            data: [0; PIPESIZE],
+            nread: 0,
+            nwrite: 0,
+            readopen: true,
+            writeopen: true,
+        }, "pipe"))
+    }
+
+    /// Close one end of the pipe,
+    /// and wake up the other end waiting on it.
+    pub fn close(&self, writable: bool) {
+        let mut pipe = self.0.lock();
+        let channel = if writable {
+            pipe.writeopen = false;
+            &pipe.nread as *const usize as usize
+        } else {
+            pipe.readopen = false;
+            &pipe.nwrite as *const usize as usize
+        };
+        drop(pipe);
+        unsafe { PROC_MANAGER.wakeup(channel); }
+    }
+
+    /// Write count bytes from user virtual address addr to the pipe,
+    /// waiting for the reader to make room.
+    /// Return the number of bytes written, which is short if addr is bad.
+    pub fn write(&self, addr: usize, count: u32) -> Result<u32, &'static str> {
+        let p = unsafe { CPU_MANAGER.my_proc() };
+        let count = count as usize;
+        let mut buf = [0u8; PIPESIZE];
+        let mut i = 0;
+        while i < count {
+            let n = cmp::min(count - i, PIPESIZE);
+            if Address::Virtual(addr + i).copy_in(buf.as_mut_ptr(), n).is_err() {
+                break
+            }
+
+            let mut j = 0;
+            let mut pipe = self.0.lock();
+            while j < n {
+                if !pipe.readopen {
+                    drop(pipe);
+                    p.excl.lock().post_signal(SIGPIPE);
+                    return Err("pipe: no reader")
+                }
+                if p.interrupted() {
+                    drop(pipe);
+                    return Err("pipe: interrupted")
+                }
+                if pipe.nwrite == pipe.nread + PIPESIZE {
+                    let channel = &pipe.nread as *const usize as usize;
+                    unsafe { PROC_MANAGER.wakeup(channel); }
+                    let channel = &pipe.nwrite as *const usize as usize;
+                    p.sleep(channel, pipe);
+                    pipe = self.0.lock();
+                    continue
+                }
+                let w = pipe.nwrite;
+                pipe.data[w % PIPESIZE] = buf[j];
+                pipe.nwrite += 1;
+                j += 1;
+            }
+            let channel = &pipe.nread as *const usize as usize;
+            drop(pipe);
+            unsafe { PROC_MANAGER.wakeup(channel); }
+            i += n;
+        }
+        Ok(i as u32)
+    }
+
+    /// Read up to count bytes from the pipe to user virtual address addr,
+    /// waiting for the writer if it is empty.
+    /// Return the number of bytes read, which is 0 at end of file,
+    /// i.e., the pipe is empty and the write end is closed.
+    pub fn read(&self, addr: usize, count: u32) -> Result<u32, &'static str> {
+        let p = unsafe { CPU_MANAGER.my_proc() };
+        let mut pipe = self.0.lock();
+        while pipe.nread == pipe.nwrite && pipe.writeopen {
+            if p.interrupted() {
+                drop(pipe);
+                return Err("pipe: interrupted")
+            }
+            let channel = &pipe.nread as *const usize as usize;
+            p.sleep(channel, pipe);
+            pipe = self.0.lock();
+        }
+
+        let mut buf = [0u8; PIPESIZE];
+        let n = cmp::min(count as usize, pipe.nwrite - pipe.nread);
+        for c in buf[..n].iter_mut() {
+            *c = pipe.data[pipe.nread % PIPESIZE];
+            pipe.nread += 1;
+        }
+        let channel = &pipe.nwrite as *const usize as usize;
+        drop(pipe);
+        unsafe { PROC_MANAGER.wakeup(channel); }
+
+        Address::Virtual(addr).copy_out(buf.as_ptr(), n)?;
+        Ok(n as u32)
+    }
+}
@@ -0,0 +1,368 @@
+//! Unix domain sockets
+//!
+//! A socket is named by bind, which creates a socket inode at the path,
+//! and is found through that inode by connect and sendto.
+//! A stream connection is a pair of pipes, one each way,
+//! queued on the listening socket until accepted.
+//! A datagram socket keeps the datagrams sent to it until received.
+//! A socket is closed with its file, though a sender may still hold it,
+//! and sending to it then fails.
+
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::cmp;
+use core::mem;
+
+use crate::consts::fs::{DGRAMMAX, NDGRAM, SOCK_DGRAM, SOCK_STREAM, SOMAXCONN};
+use crate::mm::Address;
+use crate::process::{CPU_MANAGER, PROC_MANAGER};
+use crate::spinlock::SpinLock;
+use super::{LOG, Inode, InodeType, create, namei};
+use super::pipe::Pipe;
+
+pub struct Socket {
+    stype: i32,
+    inner: SpinLock<SocketInner>,
+}
+
+struct SocketInner {
+    /// the socket inode bound to,
+    /// held so that it is not reused for another socket
+    inode: Option<Inode>,
+    state: State,
+    /// default destination of a datagram socket, set by connect
+    peer: Option<Weak<Socket>>,
+    /// datagrams received by a datagram socket
+    dgrams: VecDeque<Vec<u8>>,
+    /// set once its file is closed
+    closed: bool,
+}
+
+/// State of a stream socket.
+enum State {
+    Idle,
+    Listening {
+        /// connections not accepted yet
+        backlog: VecDeque<Conn>,
+        max: usize,
+    },
+    Connected(Conn),
+}
+
+/// One end of a stream connection.
+struct Conn {
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.rx.close(false);
+        self.tx.close(true);
+    }
+}
+
+/// The bound sockets, by the device and inode number of their socket inodes.
+static NAMES: SpinLock<Vec<(u32, u32, Weak<Socket>)>> = SpinLock::new(Vec::new(), "socket names");
+
+/// Find the socket bound to path.
+fn lookup(path: &[u8]) -> Result<Arc<Socket>, &'static str> {
+    LOG.begin_op();
+    let inode = match namei(path) {
+        Some(inode) => inode,
+        None => {
+            LOG.end_op();
+            return Err("socket: path not found")
+        }
+    };
+    let itype = inode.lock().get_itype();
+    let (dev, inum) = (inode.dev(), inode.inum());
+    drop(inode);
+    LOG.end_op();
+    if itype != InodeType::Socket {
+        return Err("socket: not a socket")
+    }
+
+    let names = NAMES.lock();
+    let sock = names.iter()
+        .find(|name| name.0 == dev && name.1 == inum)
+        .and_then(|name| name.2.upgrade());
+    drop(names);
+    sock.ok_or("socket: connection refused")
+}
+
+impl Socket {
+    pub fn new(stype: i32) -> Result<Self, &'static str> {
+        if stype != SOCK_STREAM && stype != SOCK_DGRAM {
+            return Err("socket: invalid type")
+        }
+        Ok(Self {
+            stype,
+            inner: SpinLock::new(SocketInner {
+                inode: None,
+                state: State::Idle,
+                peer: None,
+                dgrams: VecDeque::new(),
+                closed: false,
+            }, "socket"),
+        })
+    }
+
+    /// What the processes waiting on the socket sleep on.
+    #[inline]
+    fn channel(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Name the socket by creating a socket inode at path,
+    /// which must not exist.
+    pub fn bind(sock: &Arc<Self>, path: &[u8]) -> Result<(), &'static str> {
+        if sock.inner.lock().inode.is_some() {
+            return Err("bind: already bound")
+        }
+        LOG.begin_op();
+        let inode = match create(path, InodeType::Socket, 0, 0) {
+            Ok(inode) => inode,
+            Err(err) => {
+                LOG.end_op();
+                return Err(err)
+            }
+        };
+        let (dev, inum) = (inode.dev(), inode.inum());
+        let mut inner = sock.inner.lock();
+        let res = match inner.inode {
+            None => {
+                inner.inode = Some(inode);
+                Ok(())
+            }
+            // bound by another thread meanwhile
+            Some(_) => Err(inode),
+        };
+        drop(inner);
+        if let Err(inode) = res {
+            drop(inode);
+            LOG.end_op();
+            return Err("bind: already bound")
+        }
+        LOG.end_op();
+
+        NAMES.lock().push((dev, inum, Arc::downgrade(sock)));
+        Ok(())
+    }
+
+    /// Start accepting connections on a bound stream socket,
+    /// with at most backlog of them waiting.
+    pub fn listen(&self, backlog: usize) -> Result<(), &'static str> {
+        if self.stype != SOCK_STREAM {
+            return Err("listen: not a stream socket")
+        }
+        let max = cmp::max(cmp::min(backlog, SOMAXCONN), 1);
+        let mut inner = self.inner.lock();
+        let res = if inner.inode.is_none() {
+            Err("listen: not bound")
+        } else {
+            match &mut inner.state {
+                State::Idle => {
+                    inner.state = State::Listening { backlog: VecDeque::new(), max };
+                    Ok(())
+                }
+                State::Listening { max: old_max, .. } => {
+                    *old_max = max;
+                    Ok(())
+                }
+                State::Connected(_) => Err("listen: already connected"),
+            }
+        };
+        drop(inner);
+        res
+    }
+
+    /// Wait for a connection on a listening socket,
+    /// and return a new socket connected to it.
+    pub fn accept(&self) -> Result<Self, &'static str> {
+        let p = unsafe { CPU_MANAGER.my_proc() };
+        let mut inner = self.inner.lock();
+        loop {
+            let conn = match &mut inner.state {
+                State::Listening { backlog, .. } => backlog.pop_front(),
+                _ => {
+                    drop(inner);
+                    return Err("accept: not listening")
+                }
+            };
+            if let Some(conn) = conn {
+                drop(inner);
+                let sock = Self::new(self.stype)?;
+                sock.inner.lock().state = State::Connected(conn);
+                return Ok(sock)
+            }
+            if p.interrupted() {
+                drop(inner);
+                return Err("accept: interrupted")
+            }
+            p.sleep(self.channel(), inner);
+            inner = self.inner.lock();
+        }
+    }
+
+    /// Connect a stream socket to the listening socket bound to path,
+    /// or set the default destination of a datagram socket.
+    /// A stream connection is usable at once, before it is accepted.
+    pub fn connect(sock: &Arc<Self>, path: &[u8]) -> Result<(), &'static str> {
+        let target = lookup(path)?;
+        if target.stype != sock.stype {
+            return Err("connect: wrong socket type")
+        }
+        if sock.stype == SOCK_DGRAM {
+            sock.inner.lock().peer = Some(Arc::downgrade(&target));
+            return Ok(())
+        }
+
+        match sock.inner.lock().state {
+            State::Idle => {},
+            _ => return Err("connect: already connected or listening"),
+        }
+        let c2s = Arc::new(Pipe::new());
+        let s2c = Arc::new(Pipe::new());
+        let mut inner = target.inner.lock();
+        let res = match &mut inner.state {
+            State::Listening { backlog, max } if backlog.len() < *max => {
+                backlog.push_back(Conn { rx: Arc::clone(&c2s), tx: Arc::clone(&s2c) });
+                Ok(())
+            }
+            State::Listening { .. } => Err("connect: backlog full"),
+            _ => Err("connect: connection refused"),
+        };
+        drop(inner);
+        res?;
+        unsafe { PROC_MANAGER.wakeup(target.channel()); }
+
+        // the connection queued is left half-closed if lost here
+        let mut inner = sock.inner.lock();
+        let res = match inner.state {
+            State::Idle => {
+                inner.state = State::Connected(Conn { rx: s2c, tx: c2s });
+                Ok(())
+            }
+            _ => Err("connect: already connected or listening"),
+        };
+        drop(inner);
+        res
+    }
+
+    /// Send count bytes at user virtual address addr,
+    /// to the socket bound to path if given, otherwise to the connected one.
+    /// Return the number of bytes sent.
+    pub fn send(&self, addr: usize, count: u32, path: Option<&[u8]>) -> Result<u32, &'static str> {
+        if self.stype == SOCK_STREAM {
+            let inner = self.inner.lock();
+            let tx = match &inner.state {
+                State::Connected(conn) => Some(Arc::clone(&conn.tx)),
+                _ => None,
+            };
+            drop(inner);
+            return tx.ok_or("send: not connected")?.write(addr, count)
+        }
+
+        let target = match path {
+            Some(path) => lookup(path)?,
+            None => {
+                let peer = self.inner.lock().peer.clone();
+                peer.ok_or("send: not connected")?
+                    .upgrade().ok_or("send: connection refused")?
+            }
+        };
+        if target.stype != SOCK_DGRAM {
+            return Err("send: wrong socket type")
+        }
+        let len = count as usize;
+        if len > DGRAMMAX {
+            return Err("send: datagram too long")
+        }
+        let mut dgram = Vec::new();
+        dgram.resize(len, 0u8);
+        Address::Virtual(addr).copy_in(dgram.as_mut_ptr(), len)?;
+
+        let p = unsafe { CPU_MANAGER.my_proc() };
+        let mut inner = target.inner.lock();
+        loop {
+            if inner.closed {
+                drop(inner);
+                return Err("send: connection refused")
+            }
+            if inner.dgrams.len() < NDGRAM {
+                break
+            }
+            if p.interrupted() {
+                drop(inner);
+                return Err("send: interrupted")
+            }
+            p.sleep(target.channel(), inner);
+            inner = target.inner.lock();
+        }
+        inner.dgrams.push_back(dgram);
+        drop(inner);
+        unsafe { PROC_MANAGER.wakeup(target.channel()); }
+        Ok(count)
+    }
+
+    /// Receive up to count bytes to user virtual address addr.
+    /// A datagram longer than count is truncated.
+    /// Return the number of bytes received, 0 if a stream is closed by the peer.
+    pub fn recv(&self, addr: usize, count: u32) -> Result<u32, &'static str> {
+        let p = unsafe { CPU_MANAGER.my_proc() };
+        let mut inner = self.inner.lock();
+        if self.stype == SOCK_STREAM {
+            let rx = match &inner.state {
+                State::Connected(conn) => Some(Arc::clone(&conn.rx)),
+                _ => None,
+            };
+            drop(inner);
+            return rx.ok_or("recv: not connected")?.read(addr, count)
+        }
+
+        let dgram = loop {
+            if let Some(dgram) = inner.dgrams.pop_front() {
+                break dgram
+            }
+            if p.interrupted() {
+                drop(inner);
+                return Err("recv: interrupted")
+            }
+            p.sleep(self.channel(), inner);
+            inner = self.inner.lock();
+        };
+        drop(inner);
+        // wake up the senders waiting for room
+        unsafe { PROC_MANAGER.wakeup(self.channel()); }
+
+        let n = cmp::min(dgram.len(), count as usize);
+        Address::Virtual(addr).copy_out(dgram.as_ptr(), n)?;
+        Ok(n as u32)
+    }
+
+    /// Close the socket as its file is closed.
+    /// Unbind it, drop its datagrams and connections,
+    /// and wake up the senders waiting for room, which then fail.
+    pub fn close(&self) {
+        let mut inner = self.inner.lock();
+        inner.closed = true;
+        let inode = inner.inode.take();
+        let state = mem::replace(&mut inner.state, State::Idle);
+        let dgrams = mem::take(&mut inner.dgrams);
+        inner.peer = None;
+        drop(inner);
+        drop(state);
+        drop(dgrams);
+        unsafe { PROC_MANAGER.wakeup(self.channel()); }
+
+        if let Some(inode) = inode {
+            let (dev, inum) = (inode.dev(), inode.inum());
+            NAMES.lock().retain(|name| name.0 != dev || name.1 != inum);
+            LOG.begin_op();
+            drop(inode);
+            LOG.end_op();
+        }
+    }
+}
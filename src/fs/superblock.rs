@@ -4,8 +4,12 @@ use core::ptr;
 use core::mem::{self, MaybeUninit};
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::consts::fs::FSMAGIC;
+use crate::consts::fs::{FSMAGIC, BSIZE};
 use super::{BCACHE, BufData};
+use super::inode::IPB;
+
+/// Bitmap bits per block
+pub const BPB: u32 = (BSIZE * 8) as u32;
 
 pub static mut SUPER_BLOCK: SuperBlock = SuperBlock::uninit();
 
@@ -70,6 +74,24 @@ impl SuperBlock {
         let sb = self.read();
         sb.size
     }
+
+    /// The total count of inodes in the disk.
+    pub fn inode_size(&self) -> u32 {
+        let sb = self.read();
+        sb.ninodes
+    }
+
+    /// Block number of the block containing inode inum.
+    pub fn locate_inode(&self, inum: u32) -> u32 {
+        let sb = self.read();
+        sb.inodestart + inum / (IPB as u32)
+    }
+
+    /// Block number of the free map block containing the bit for block blockno.
+    pub fn bitmap_blockno(&self, blockno: u32) -> u32 {
+        let sb = self.read();
+        sb.bmapstart + blockno / BPB
+    }
 }
 
 /// Raw super block describes the disk layout.
@@ -34,18 +34,23 @@ mod spinlock;
 mod sleeplock;
 mod start;
 mod trap;
+mod timer;
 mod driver;
 mod plic;
 
 #[cfg(feature = "unit_test")]
 fn test_main_entry() {
-    use proc::cpu_id;
+    use process::CpuManager;
 
-    let cpu_id = unsafe { cpu_id() };
+    let cpu_id = unsafe { CpuManager::cpu_id() };
 
     // test cases only needed to be executed with a single hart/kernel-thread
     if cpu_id == 0 {
         spinlock::tests::smoke();
+        process::sched::stride::tests::share();
+        timer::tests::far_deadline();
+        timer::tests::stale_cancel();
+        timer::tests::catch_up();
     }
 
     // test cases needed to be executed with multiple harts/kernel-threads
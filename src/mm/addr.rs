@@ -1,8 +1,10 @@
 use core::convert::TryFrom;
 use core::result::Result;
 use core::ops::{Add, Sub};
+use core::ptr;
 
 use crate::consts::{PGMASK, PGMASKLEN, PGSHIFT, PGSIZE, PHYSTOP, MAXVA, ConstAddr};
+use crate::process::CPU_MANAGER;
 
 pub trait Addr {
     fn data_ref(&self) -> &usize;
@@ -137,3 +139,50 @@ impl Sub for VirtAddr {
         Self(self.0 - other.0)
     }
 }
+
+/// Address used when moving data in or out of the kernel.
+/// It is either a user virtual address of the current process,
+/// or a kernel address.
+#[derive(Clone, Copy, Debug)]
+pub enum Address {
+    Virtual(usize),
+    Kernel(*const u8),
+}
+
+impl Address {
+    /// Return the address moved forward by count bytes.
+    pub fn offset(self, count: usize) -> Self {
+        match self {
+            Self::Virtual(p) => Self::Virtual(p + count),
+            Self::Kernel(p) => Self::Kernel(unsafe { p.add(count) }),
+        }
+    }
+
+    /// Copy count bytes from kernel's src to this address.
+    pub fn copy_out(self, src: *const u8, count: usize) -> Result<(), &'static str> {
+        match self {
+            Self::Virtual(dst) => {
+                let p = unsafe { CPU_MANAGER.my_proc() };
+                unsafe { (*p.data.get()).copy_out(src, dst, count) }
+            }
+            Self::Kernel(dst) => {
+                unsafe { ptr::copy(src, dst as *mut u8, count); }
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy count bytes from this address to kernel's dst.
+    pub fn copy_in(self, dst: *mut u8, count: usize) -> Result<(), &'static str> {
+        match self {
+            Self::Virtual(src) => {
+                let p = unsafe { CPU_MANAGER.my_proc() };
+                unsafe { (*p.data.get()).copy_in(src, dst, count) }
+            }
+            Self::Kernel(src) => {
+                unsafe { ptr::copy(src, dst, count); }
+                Ok(())
+            }
+        }
+    }
+}
@@ -492,9 +492,8 @@ fn blk_size(k: usize) -> usize {
 
 #[cfg(feature = "unit_test")]
 pub mod tests {
-    use super::*;
     use crate::consts;
-    use crate::proc::cpu_id;
+    use crate::process::CpuManager;
     use crate::mm::pagetable::PageTable;
     use core::sync::atomic::{AtomicU8, Ordering};
 
@@ -502,13 +501,13 @@ pub mod tests {
         // use NSMP to synchronize testing pr's spinlock
         static NSMP: AtomicU8 = AtomicU8::new(0);
         NSMP.fetch_add(1, Ordering::Relaxed);
-        while NSMP.load(Ordering::Relaxed) != NSMP as u8 {}
+        while NSMP.load(Ordering::Relaxed) != consts::NSMP as u8 {}
 
-        let id = unsafe { cpu_id() };
+        let id = unsafe { CpuManager::cpu_id() };
 
         for _ in 0..10 {
-            let page_table = PageTable::new();
-            println!("hart {} alloc page table at {:#x}", id, page_table.addr());
+            let page_table = PageTable::uvm_create();
+            println!("hart {} alloc page table at {:#x}", id, &*page_table as *const PageTable as usize);
         }
 
         NSMP.fetch_sub(1, Ordering::Relaxed);
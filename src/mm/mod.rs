@@ -1,8 +1,10 @@
+use alloc::alloc::{alloc_zeroed, Layout};
 use alloc::boxed::Box;
 
-use crate::consts::PGSIZE;
+use crate::consts::{KERNBASE, PGSIZE, PHYSTOP};
+use crate::spinlock::SpinLock;
 
-pub use addr::{Addr, PhysAddr, VirtAddr};
+pub use addr::{Addr, PhysAddr, VirtAddr, Address};
 pub use kvm::{kvm_init, kvm_init_hart, kvm_map, kvm_pa};
 pub use pagetable::{PageTable, PteFlag};
 pub use kalloc::{KernelHeap, KERNEL_HEAP};
@@ -27,4 +29,69 @@ impl RawPage {
         let boxed_page = Box::<Self>::new_zeroed().assume_init();
         Box::into_raw(boxed_page) as usize
     }
+
+    /// Try to allocate an zeroed physical page.
+    /// Unlike new_zeroed, it does not panic when running out of memory.
+    pub unsafe fn try_new_zeroed() -> Result<usize, ()> {
+        let ptr = alloc_zeroed(Layout::new::<Self>());
+        if ptr.is_null() {
+            Err(())
+        } else {
+            Ok(ptr as usize)
+        }
+    }
+
+    /// Free the page handed out by new_zeroed or try_new_zeroed.
+    pub unsafe fn from_raw_and_drop(raw: usize) {
+        drop(Box::from_raw(raw as *mut Self));
+    }
+
+    /// Add a reference to the physical page at raw,
+    /// which is then shared, e.g., by copy-on-write fork.
+    pub fn share(raw: usize) {
+        let mut refs = PAGE_REFS.lock();
+        refs[page_index(raw)] += 1;
+        drop(refs);
+    }
+
+    /// Check if the physical page at raw is referenced more than once.
+    pub fn is_shared(raw: usize) -> bool {
+        PAGE_REFS.lock()[page_index(raw)] > 0
+    }
+
+    /// Drop a reference to the physical page at raw,
+    /// and free it if that is the last one.
+    pub unsafe fn put(raw: usize) {
+        let mut refs = PAGE_REFS.lock();
+        let index = page_index(raw);
+        if refs[index] > 0 {
+            refs[index] -= 1;
+            drop(refs);
+        } else {
+            drop(refs);
+            Self::from_raw_and_drop(raw);
+        }
+    }
+}
+
+/// Number of physical pages that can be handed out to user.
+const NPAGES: usize = (PHYSTOP.as_usize() - KERNBASE.as_usize()) / PGSIZE;
+
+/// Extra references to each physical page besides its first owner,
+/// so a page handed out by RawPage starts with zero.
+static PAGE_REFS: SpinLock<[u16; NPAGES]> = SpinLock::new([0; NPAGES], "page refs");
+
+#[inline]
+fn page_index(raw: usize) -> usize {
+    (raw - usize::from(KERNBASE)) / PGSIZE
+}
+
+#[inline]
+pub fn pg_round_up(addr: usize) -> usize {
+    (addr + (PGSIZE - 1)) & !(PGSIZE - 1)
+}
+
+#[inline]
+pub fn pg_round_down(addr: usize) -> usize {
+    addr & !(PGSIZE - 1)
 }
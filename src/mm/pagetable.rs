@@ -1,11 +1,13 @@
 use array_macro::array;
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::convert::TryFrom;
 use core::ptr;
 
 use crate::consts::{PGSHIFT, PGSIZE, SATP_SV39, SV39FLAGLEN, USERTEXT};
-use super::{Addr, PhysAddr, VirtAddr, RawPage};
+use crate::process::CPU_MANAGER;
+use super::{Addr, PhysAddr, VirtAddr, RawPage, pg_round_down, pg_round_up};
 
 bitflags! {
     pub struct PteFlag: usize {
@@ -17,6 +19,8 @@ bitflags! {
         const G = 1 << 5;
         const A = 1 << 6;
         const D = 1 << 7;
+        /// RSW bit, the page is shared by copy-on-write
+        const COW = 1 << 8;
     }
 }
 
@@ -41,6 +45,45 @@ impl PageTableEntry {
         (self.data & (PteFlag::U.bits())) > 0
     }
 
+    /// A leaf PTE maps a physical page,
+    /// while a non-leaf one points to the next level page table.
+    #[inline]
+    fn is_leaf(&self) -> bool {
+        (self.data & (PteFlag::R | PteFlag::W | PteFlag::X).bits()) > 0
+    }
+
+    #[inline]
+    fn is_writable(&self) -> bool {
+        (self.data & (PteFlag::W.bits())) > 0
+    }
+
+    #[inline]
+    fn is_cow(&self) -> bool {
+        (self.data & (PteFlag::COW.bits())) > 0
+    }
+
+    #[inline]
+    fn read_flags(&self) -> PteFlag {
+        PteFlag::from_bits_truncate(self.data)
+    }
+
+    #[inline]
+    fn clear_user(&mut self) {
+        self.data &= !PteFlag::U.bits();
+    }
+
+    /// Mark the page dirty, as the hardware does on a user store.
+    #[inline]
+    fn set_dirty(&mut self) {
+        self.data |= (PteFlag::A | PteFlag::D).bits();
+    }
+
+    /// Turn a writable mapping into a read-only copy-on-write one.
+    #[inline]
+    fn set_cow(&mut self) {
+        self.data = (self.data & !PteFlag::W.bits()) | PteFlag::COW.bits();
+    }
+
     #[inline]
     fn as_page_table(&self) -> *mut PageTable {
         ((self.data >> SV39FLAGLEN) << PGSHIFT) as *mut PageTable
@@ -155,6 +198,21 @@ impl PageTable {
         unsafe { Some(&mut pgt.as_mut().unwrap().data[va.page_num(0)]) }
     }
 
+    /// Same as walk_alloc, but never allocate new page table.
+    fn walk_mut(&mut self, va: VirtAddr) -> Option<&mut PageTableEntry> {
+        let mut pgt = self as *mut PageTable;
+        for level in (1..=2).rev() {
+            let pte = unsafe { &mut pgt.as_mut().unwrap().data[va.page_num(level)] };
+
+            if pte.is_valid() {
+                pgt = pte.as_page_table();
+            } else {
+                return None
+            }
+        }
+        unsafe { Some(&mut pgt.as_mut().unwrap().data[va.page_num(0)]) }
+    }
+
     pub fn walk(&self, va: VirtAddr) -> Option<&PageTableEntry> {
         let mut pgt = self as *const PageTable;
         for level in (1..=2).rev() {
@@ -191,9 +249,284 @@ impl PageTable {
         unsafe { ptr::copy_nonoverlapping(code.as_ptr(), mem, code.len()); }
     }
 
+    /// Allocate PTEs and physical memory to grow process from old_sz to
+    /// new_sz, which need not be page aligned.
+    /// The pages are mapped with perm, plus R and U.
+    /// Return the new size, or Err if out of memory,
+    /// in which case the newly allocated pages are freed.
+    pub fn uvm_alloc(&mut self, old_sz: usize, new_sz: usize, perm: PteFlag)
+        -> Result<usize, &'static str>
+    {
+        if new_sz < old_sz {
+            return Ok(old_sz)
+        }
+        VirtAddr::try_from(new_sz)?;
+
+        let start = pg_round_up(old_sz);
+        for a in (start..new_sz).step_by(PGSIZE) {
+            let mem = match unsafe { RawPage::try_new_zeroed() } {
+                Ok(mem) => mem,
+                Err(_) => {
+                    self.uvm_dealloc(a, old_sz);
+                    return Err("uvm_alloc: out of memory")
+                }
+            };
+            if let Err(err) = self.map_pages(
+                VirtAddr::try_from(a).unwrap(),
+                PGSIZE,
+                PhysAddr::try_from(mem).unwrap(),
+                perm | PteFlag::R | PteFlag::U)
+            {
+                unsafe { RawPage::from_raw_and_drop(mem); }
+                self.uvm_dealloc(a, old_sz);
+                return Err(err)
+            }
+        }
+
+        Ok(new_sz)
+    }
+
+    /// Deallocate user pages to bring the process size from old_sz to
+    /// new_sz. old_sz and new_sz need not be page-aligned, nor does new_sz
+    /// need to be less than old_sz. old_sz can be larger than the actual
+    /// process size. Return the new process size.
+    pub fn uvm_dealloc(&mut self, old_sz: usize, new_sz: usize) -> usize {
+        if new_sz >= old_sz {
+            return old_sz
+        }
+
+        let new_up = pg_round_up(new_sz);
+        let old_up = pg_round_up(old_sz);
+        if new_up < old_up {
+            self.uvm_unmap(new_up, (old_up - new_up) / PGSIZE, true);
+        }
+
+        new_sz
+    }
+
+    /// Remove npages of mappings starting from va. va must be
+    /// page-aligned. The mappings need not exist,
+    /// since user memory is allocated lazily.
+    /// Optionally free the physical memory,
+    /// once no other cpu running this page table holds it in its TLB.
+    pub fn uvm_unmap(&mut self, va: usize, npages: usize, freeing: bool) {
+        if va % PGSIZE != 0 {
+            panic!("uvm_unmap: va not aligned");
+        }
+
+        let mut pages = Vec::new();
+        for a in (va..(va + npages * PGSIZE)).step_by(PGSIZE) {
+            let pte = match self.walk_mut(VirtAddr::try_from(a).unwrap()) {
+                Some(pte) => pte,
+                None => continue,
+            };
+            if !pte.is_valid() {
+                continue
+            }
+            if !pte.is_leaf() {
+                panic!("uvm_unmap: va={:#x} not a leaf", a);
+            }
+            if freeing {
+                pages.push(pte.as_phys_addr().as_usize());
+            }
+            pte.write_zero();
+        }
+
+        if !pages.is_empty() {
+            unsafe { CPU_MANAGER.flush_tlb(self.as_satp()); }
+            for pa in pages {
+                unsafe { RawPage::put(pa); }
+            }
+        }
+    }
+
+    /// Free user memory pages [0, sz),
+    /// then free page-table pages below the root,
+    /// which is freed along with its Box.
+    /// Other mappings, e.g., trampoline and trapframe,
+    /// should be removed beforehand.
+    pub fn uvm_free(&mut self, sz: usize) {
+        if sz > 0 {
+            self.uvm_unmap(0, pg_round_up(sz) / PGSIZE, true);
+        }
+        self.free_walk();
+    }
+
+    /// Recursively free page-table pages.
+    /// All leaf mappings must already have been removed.
+    fn free_walk(&mut self) {
+        for pte in self.data.iter_mut() {
+            if !pte.is_valid() {
+                continue
+            }
+            if pte.is_leaf() {
+                panic!("free_walk: leaf");
+            }
+            let child = pte.as_page_table();
+            unsafe {
+                child.as_mut().unwrap().free_walk();
+                drop(Box::from_raw(child));
+            }
+            pte.write_zero();
+        }
+    }
+
+    /// Given a parent process's page table, copy its memory [0, sz)
+    /// into a child's page table.
+    /// The physical pages are shared rather than copied,
+    /// see uvm_share.
+    /// Free any mappings in the child on failure.
+    pub fn uvm_copy(&mut self, child: &mut Self, sz: usize) -> Result<(), &'static str> {
+        self.uvm_share(child, 0, pg_round_up(sz), true)
+    }
+
+    /// Share the mapped pages in [start, end) with a child's page table.
+    /// start and end must be page-aligned.
+    /// If cow is true, writable pages become read-only copy-on-write ones
+    /// in both, and get copied at the first store page fault, see cow_fault.
+    /// The stale TLB entries of the cpus running the parent's threads are flushed,
+    /// so none of them writes to a page now shared with the child.
+    /// Free any mappings in the child on failure.
+    pub fn uvm_share(&mut self, child: &mut Self, start: usize, end: usize, cow: bool)
+        -> Result<(), &'static str>
+    {
+        let mut res = Ok(());
+        let mut downgraded = false;
+        for i in (start..end).step_by(PGSIZE) {
+            let va = VirtAddr::try_from(i).unwrap();
+            // skip the pages not allocated yet
+            let pte = match self.walk_mut(va) {
+                Some(pte) => pte,
+                None => continue,
+            };
+            if !pte.is_valid() {
+                continue
+            }
+            if cow && pte.is_writable() {
+                pte.set_cow();
+                downgraded = true;
+            }
+            let pa = pte.as_phys_addr();
+            let flags = pte.read_flags();
+
+            RawPage::share(pa.as_usize());
+            if let Err(err) = child.map_pages(va, PGSIZE, pa, flags) {
+                unsafe { RawPage::put(pa.as_usize()); }
+                child.uvm_unmap(start, (i - start) / PGSIZE, true);
+                res = Err(err);
+                break
+            }
+        }
+        if downgraded {
+            unsafe { CPU_MANAGER.flush_tlb(self.as_satp()); }
+        }
+        res
+    }
+
+    /// Return the flags of the valid mapping at va, if any.
+    pub fn walk_flags(&self, va: usize) -> Option<PteFlag> {
+        match self.walk(VirtAddr::try_from(va).ok()?) {
+            Some(pte) if pte.is_valid() => Some(pte.read_flags()),
+            _ => None,
+        }
+    }
+
+    /// Handle a user page fault at virtual address va,
+    /// where the user memory is [0, sz).
+    /// Map a zeroed page if it is not allocated yet,
+    /// or break the copy-on-write sharing if it is a store.
+    /// Return Err if the access is illegal.
+    pub fn uvm_fault(&mut self, va: usize, sz: usize, store: bool) -> Result<(), &'static str> {
+        if va >= sz {
+            return Err("page fault: va above the process size")
+        }
+        let (valid, user, cow) = match self.walk(VirtAddr::try_from(va)?) {
+            Some(pte) => (pte.is_valid(), pte.is_user(), pte.is_cow()),
+            None => (false, false, false),
+        };
+        if !valid {
+            self.uvm_lazy(va)
+        } else if !user {
+            Err("page fault: va in the guard page")
+        } else if store && cow {
+            self.cow_fault(va)
+        } else {
+            Err("page fault: access not permitted")
+        }
+    }
+
+    /// Allocate and map a zeroed user page at va,
+    /// if it has not been allocated yet.
+    pub fn uvm_lazy(&mut self, va: usize) -> Result<(), &'static str> {
+        let va = VirtAddr::try_from(pg_round_down(va))?;
+        if let Some(pte) = self.walk(va) {
+            if pte.is_valid() {
+                return Ok(())
+            }
+        }
+
+        let mem = unsafe { RawPage::try_new_zeroed() }
+            .map_err(|_| "uvm_lazy: out of memory")?;
+        if let Err(err) = self.map_pages(
+            va,
+            PGSIZE,
+            PhysAddr::try_from(mem).unwrap(),
+            PteFlag::R | PteFlag::W | PteFlag::U)
+        {
+            unsafe { RawPage::from_raw_and_drop(mem); }
+            return Err(err)
+        }
+        Ok(())
+    }
+
+    /// Handle a store page fault at user virtual address va.
+    /// If the page is copy-on-write, give this page table its own
+    /// writable copy, or take it over if no one else shares it.
+    /// Return Err if it is not a copy-on-write fault.
+    pub fn cow_fault(&mut self, va: usize) -> Result<(), &'static str> {
+        let va = VirtAddr::try_from(va)?;
+        let pte = self.walk_mut(va).ok_or("cow_fault: va not mapped")?;
+        if !pte.is_valid() || !pte.is_user() {
+            return Err("cow_fault: va not mapped for user")
+        }
+        if !pte.is_cow() {
+            return Err("cow_fault: va not copy-on-write")
+        }
+
+        let pa = pte.as_phys_addr();
+        let flags = (pte.read_flags() - PteFlag::COW) | PteFlag::W;
+        let copied = RawPage::is_shared(pa.as_usize());
+        if copied {
+            let mem = unsafe { RawPage::try_new_zeroed() }
+                .map_err(|_| "cow_fault: out of memory")?;
+            unsafe { ptr::copy_nonoverlapping(pa.as_ptr(), mem as *mut u8, PGSIZE); }
+            pte.write_perm(PhysAddr::try_from(mem).unwrap(), flags);
+        } else {
+            // the last reference, take it over
+            pte.write_perm(pa, flags);
+        }
+
+        // other threads must neither keep reading the old page,
+        // nor fault again on the read-only mapping of it
+        unsafe { CPU_MANAGER.flush_tlb(self.as_satp()); }
+        if copied {
+            unsafe { RawPage::put(pa.as_usize()); }
+        }
+        Ok(())
+    }
+
+    /// Mark a PTE invalid for user access.
+    /// Used by exec for the user stack guard page.
+    pub fn uvm_clear(&mut self, va: usize) {
+        match self.walk_mut(VirtAddr::try_from(va).unwrap()) {
+            Some(pte) => pte.clear_user(),
+            None => panic!("uvm_clear: va={:#x} not mapped", va),
+        }
+    }
+
     /// Return the mapped physical address(page aligned)
     /// va need not be page aligned
-    fn walk_addr(&self, va: VirtAddr)
+    pub fn walk_addr(&self, va: VirtAddr)
         -> Result<PhysAddr, &'static str>
     {
         match self.walk(va) {
@@ -256,4 +589,66 @@ impl PageTable {
 
         Err("copy_in_str: dst not enough space")
     }
+
+    /// Return the mapped physical address(page aligned) for user to write,
+    /// breaking the copy-on-write sharing if needed.
+    /// va need not be page aligned
+    fn walk_addr_write(&mut self, va: VirtAddr)
+        -> Result<PhysAddr, &'static str>
+    {
+        let (writable, cow) = match self.walk(va) {
+            Some(pte) => (pte.is_writable(), pte.is_cow()),
+            None => return Err("va not mapped"),
+        };
+        if cow {
+            self.cow_fault(va.as_usize())?;
+        } else if !writable {
+            return Err("pte not writable")
+        }
+        // so that a shared mapping gets written back
+        self.walk_mut(va).unwrap().set_dirty();
+        self.walk_addr(va)
+    }
+
+    /// Copy from kernel to user.
+    /// Copy count bytes from src to virtual address dst in this page table.
+    pub fn copy_out(&mut self, mut src: *const u8, mut dst: usize, mut count: usize)
+        -> Result<(), &'static str>
+    {
+        while count > 0 {
+            let mut base = VirtAddr::try_from(dst)?;
+            base.pg_round_down();
+            let distance = dst - base.as_usize();
+            let pa = self.walk_addr_write(base)?;
+            let n = if PGSIZE - distance > count { count } else { PGSIZE - distance };
+            unsafe {
+                ptr::copy(src, (pa.as_usize() + distance) as *mut u8, n);
+                src = src.add(n);
+            }
+            count -= n;
+            dst += n;
+        }
+        Ok(())
+    }
+
+    /// Copy from user to kernel.
+    /// Copy count bytes to dst from virtual address src in this page table.
+    pub fn copy_in(&self, mut src: usize, mut dst: *mut u8, mut count: usize)
+        -> Result<(), &'static str>
+    {
+        while count > 0 {
+            let mut base = VirtAddr::try_from(src)?;
+            base.pg_round_down();
+            let distance = src - base.as_usize();
+            let pa = self.walk_addr(base)?;
+            let n = if PGSIZE - distance > count { count } else { PGSIZE - distance };
+            unsafe {
+                ptr::copy((pa.as_usize() + distance) as *const u8, dst, n);
+                dst = dst.add(n);
+            }
+            count -= n;
+            src += n;
+        }
+        Ok(())
+    }
 }
@@ -84,17 +84,17 @@ fn abort() -> ! {
 
 #[cfg(feature = "unit_test")]
 pub mod tests {
-    use crate::consts::NSMP;
-    use crate::proc::cpu_id;
+    use crate::consts;
+    use crate::process::CpuManager;
     use core::sync::atomic::{AtomicU8, Ordering};
 
     pub fn println_simo() {
-        let cpu_id = unsafe { cpu_id() };
+        let cpu_id = unsafe { CpuManager::cpu_id() };
 
         // use NSMP to synchronize testing pr's spinlock
         static NSMP: AtomicU8 = AtomicU8::new(0);
         NSMP.fetch_add(1, Ordering::Relaxed);
-        while NSMP.load(Ordering::Relaxed) != NSMP as u8 {}
+        while NSMP.load(Ordering::Relaxed) != consts::NSMP as u8 {}
 
         for i in 0..10 {
             println!("println_mul_hart{}: hart {}", i, cpu_id);
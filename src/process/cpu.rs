@@ -1,22 +1,31 @@
 use array_macro::array;
 
 use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
 
 use crate::register::{tp, sstatus};
-use crate::spinlock::SpinLockGuard;
-use crate::consts::NCPU;
+use crate::spinlock::{SpinLock, SpinLockGuard};
+use crate::timer;
+use crate::consts::{NCPU, NSMP};
 use super::{Context, PROC_MANAGER, Proc, ProcState, proc::ProcExcl};
+use super::sched::{Policy, SchedInfo, Scheduler};
 
 pub static mut CPU_MANAGER: CpuManager = CpuManager::new();
 
+/// Affinity of a process allowed to run on every hart.
+pub const ALL_HARTS: usize = (1 << NSMP) - 1;
+
 pub struct CpuManager {
-    table: [Cpu; NCPU]
+    table: [Cpu; NCPU],
+    /// harts waiting for an interrupt in idle, a bit per hart
+    idle: AtomicUsize,
 }
 
 impl CpuManager {
     const fn new() -> Self {
         Self {
             table: array![_ => Cpu::new(); NCPU],
+            idle: AtomicUsize::new(0),
         }
     }
 
@@ -60,6 +69,111 @@ impl CpuManager {
         p
     }
 
+    /// Queue the process at slot i, which just became RUNNABLE,
+    /// on this cpu's run queue if affinity allows,
+    /// otherwise on the least loaded cpu it allows.
+    /// Interrupts must be disabled, e.g., by holding the process's excl lock.
+    pub unsafe fn enqueue(&self, i: usize, info: &mut SchedInfo, affinity: usize) {
+        let id = Self::cpu_id();
+        let target = if affinity & (1 << id) != 0 {
+            id
+        } else {
+            (0..NSMP)
+                .filter(|&other| affinity & (1 << other) != 0)
+                .min_by_key(|&other| self.table[other].runq.lock().len())
+                .expect("enqueue: empty affinity")
+        };
+        self.table[target].runq.lock().enqueue(i, info);
+
+        // wake up an idle hart to run or steal it, see idle
+        fence(Ordering::SeqCst);
+        let idle = self.idle.load(Ordering::SeqCst) & affinity & !(1 << id);
+        if idle != 0 {
+            timer::kick(idle.trailing_zeros() as usize);
+        }
+    }
+
+    /// Move the RUNNABLE process at slot i onto a run queue its new affinity allows.
+    /// It is left alone if no run queue holds it,
+    /// i.e., a cpu has just picked it and it moves at its next yield.
+    /// Caller must hold its excl lock, which info is from.
+    pub unsafe fn requeue(&self, i: usize, info: &mut SchedInfo, affinity: usize) {
+        for c in self.table[..NSMP].iter() {
+            if c.runq.lock().remove(i, info) {
+                self.enqueue(i, info, affinity);
+                return
+            }
+        }
+    }
+
+    /// Take out the next process for the cpu id to run.
+    /// If its own run queue has none it may run,
+    /// steal one from the busiest other cpu having one it may run.
+    pub fn pick_runnable(&self, id: usize) -> Option<usize> {
+        let allowed = |i: usize| unsafe { PROC_MANAGER.table[i].allowed_on(id) };
+        if let Some(i) = self.table[id].runq.lock().pick(allowed) {
+            return Some(i)
+        }
+        let victim = (0..NSMP)
+            .filter(|&other| other != id)
+            .filter_map(|other| {
+                let runq = self.table[other].runq.lock();
+                if runq.any(allowed) {
+                    Some((other, runq.len()))
+                } else {
+                    None
+                }
+            })
+            .max_by_key(|&(_, len)| len)?
+            .0;
+        self.table[victim].runq.lock().pick(allowed)
+    }
+
+    /// Charge a clock tick to the running process at slot i.
+    /// Return true if it should give up the cpu.
+    /// Interrupts must be disabled, e.g., by holding the process's excl lock.
+    pub unsafe fn sched_tick(&self, i: usize, info: &mut SchedInfo) -> bool {
+        self.my_cpu().runq.lock().tick(i, info)
+    }
+
+    /// Called once per clock tick, on cpu 0 only.
+    pub fn sched_clock() {
+        Policy::clock();
+    }
+
+    /// Record that this cpu returns to user mode with the user page table satp.
+    /// Interrupts must be disabled.
+    pub unsafe fn enter_user(&self, satp: usize) {
+        self.my_cpu().user_satp.store(satp, Ordering::SeqCst);
+    }
+
+    /// Record that this cpu trapped from user mode,
+    /// which flushed its TLB, see uservec in trampoline.S.
+    /// Interrupts must be disabled.
+    pub unsafe fn leave_user(&self) {
+        let c = self.my_cpu();
+        c.user_satp.store(0, Ordering::SeqCst);
+        c.user_traps.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Make sure no cpu holds stale TLB entries of the user page table satp,
+    /// after mappings in it are removed or downgraded,
+    /// so that the pages unmapped can be freed.
+    /// A cpu in the kernel holds none, since the trampoline flushes the TLB
+    /// on each switch, so the cpus running satp in user mode are kicked into a trap.
+    pub fn flush_tlb(&self, satp: usize) {
+        fence(Ordering::SeqCst);
+        for (id, c) in self.table[..NSMP].iter().enumerate() {
+            let traps = c.user_traps.load(Ordering::SeqCst);
+            if c.user_satp.load(Ordering::SeqCst) != satp {
+                continue
+            }
+            timer::kick(id);
+            while c.user_satp.load(Ordering::SeqCst) == satp
+                && c.user_traps.load(Ordering::SeqCst) == traps {}
+        }
+    }
+
     /// Scheduler loop, never return
     /// jumped from rust_main in rmain.rs
     /// called simultaneously by different harts
@@ -68,6 +182,7 @@ impl CpuManager {
             fn swtch(old: *mut Context, new: *mut Context);
         }
 
+        let id = Self::cpu_id();
         let c = self.my_cpu_mut();
 
         loop {
@@ -75,10 +190,13 @@ impl CpuManager {
             sstatus::intr_on();
 
             // use ProcManager to find a runnable process
-            match PROC_MANAGER.alloc_runnable() {
+            match PROC_MANAGER.alloc_runnable(id) {
                 Some(p) => {
                     c.proc = p as *mut _;
                     let mut guard = p.excl.lock();
+                    // interrupts are off from here on until it runs
+                    timer::arm(true);
+                    assert_eq!(guard.state, ProcState::RUNNABLE);
                     guard.state = ProcState::RUNNING;
 
                     swtch(&mut c.scheduler as *mut Context,
@@ -90,21 +208,47 @@ impl CpuManager {
                     c.proc = ptr::null_mut();
                     drop(guard);
                 },
-                None => {},
+                None => CPU_MANAGER.idle(id),
             }
         }
     }
+
+    /// Wait for an interrupt when there is nothing to run, instead of spinning.
+    /// Processes queued but not allowed on this hart do not count.
+    /// Interrupts are disabled from the last look at the run queues on,
+    /// so that a process queued meanwhile is not missed,
+    /// and wfi still returns once an interrupt is pending.
+    unsafe fn idle(&self, id: usize) {
+        sstatus::intr_off();
+        self.idle.fetch_or(1 << id, Ordering::SeqCst);
+        let allowed = |i: usize| PROC_MANAGER.table[i].allowed_on(id);
+        if !self.table[..NSMP].iter().any(|c| c.runq.lock().any(allowed)) {
+            timer::arm(false);
+            llvm_asm!("wfi"::::"volatile");
+        }
+        self.idle.fetch_and(!(1 << id), Ordering::SeqCst);
+    }
 }
 
 /// Cpu contains current info about the running cpu 
 ///
 /// no need to bind a spinlock to it,
-/// since only one hart will use this struct
+/// since only one hart will use this struct,
+/// except the run queue, which other harts steal from.
 pub struct Cpu {
     proc: *mut Proc,
     scheduler: Context,
     noff: u8,
     intena: bool,
+    /// the last clock tick this cpu took
+    tick: usize,
+    /// RUNNABLE processes to run on this cpu, kept by the scheduling policy
+    runq: SpinLock<Policy>,
+    /// the user page table this cpu runs in user mode, 0 if in the kernel,
+    /// see CpuManager::flush_tlb
+    user_satp: AtomicUsize,
+    /// number of traps from user mode so far
+    user_traps: AtomicUsize,
 }
 
 impl Cpu {
@@ -114,6 +258,10 @@ impl Cpu {
             scheduler: Context::new(),
             noff: 0,
             intena: false,
+            tick: 0,
+            runq: SpinLock::new(Policy::new(), "run queue"),
+            user_satp: AtomicUsize::new(0),
+            user_traps: AtomicUsize::new(0),
         }
     }
 
@@ -151,7 +299,25 @@ impl Cpu {
         guard
     }
 
-    /// Yield the holding process if any and it's RUNNING.
+    /// Record the clock tick at a timer interrupt.
+    /// Return true if it is a new one.
+    pub fn new_tick(&mut self, tick: usize) -> bool {
+        if tick > self.tick {
+            self.tick = tick;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Check if a process is running on this cpu.
+    #[inline]
+    pub fn has_proc(&self) -> bool {
+        !self.proc.is_null()
+    }
+
+    /// Yield the holding process if any and it's RUNNING,
+    /// and the scheduling policy preempts it at this clock tick.
     /// Directly return if none.
     pub fn yield_proc(&mut self) {
         if !self.proc.is_null() {
@@ -160,7 +326,10 @@ impl Cpu {
             };
             if guard.state == ProcState::RUNNING {
                 drop(guard);
-                unsafe { self.proc.as_mut().unwrap().yielding(); }
+                let p = unsafe { self.proc.as_mut().unwrap() };
+                if p.clock_tick() {
+                    p.yielding();
+                }
             } else {
                 drop(guard);
             }
@@ -1,23 +1,316 @@
 //! ELF loader
 
-use super::Proc;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cmp;
+use core::convert::TryFrom;
+use core::mem;
+
+use crate::consts::{MAXARG, PGSIZE};
+use crate::fs::{LOG, InodeData, namei};
+use crate::mm::{Address, Addr, PageTable, PteFlag, VirtAddr, pg_round_up};
+use crate::sleeplock::SleepLock;
+use super::proc::Proc;
+use super::space::UserSpace;
+
+/// "\x7FELF" in little endian
+const ELF_MAGIC: u32 = 0x464C457F;
+const ELF_CLASS_64: u8 = 2;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_MACHINE_RISCV: u16 = 243;
+
+/// Values for ProgHeader type
+const ELF_PROG_LOAD: u32 = 1;
+
+/// Flag bits for ProgHeader flags
+const ELF_PROG_FLAG_EXEC: u32 = 1;
+const ELF_PROG_FLAG_WRITE: u32 = 2;
+
+/// File header
+#[repr(C)]
+struct ElfHeader {
+    magic: u32,
+    elf: [u8; 12],
+    etype: u16,
+    machine: u16,
+    version: u32,
+    entry: usize,
+    phoff: usize,
+    shoff: usize,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+/// Program section header
+#[repr(C)]
+struct ProgHeader {
+    ptype: u32,
+    flags: u32,
+    off: usize,
+    vaddr: usize,
+    paddr: usize,
+    filesz: usize,
+    memsz: usize,
+    align: usize,
+}
+
+impl ElfHeader {
+    fn empty() -> Self {
+        unsafe { mem::MaybeUninit::zeroed().assume_init() }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == ELF_MAGIC
+            && self.elf[0] == ELF_CLASS_64
+            && self.etype == ELF_TYPE_EXEC
+            && self.machine == ELF_MACHINE_RISCV
+            && self.phentsize as usize == mem::size_of::<ProgHeader>()
+    }
+}
+
+impl ProgHeader {
+    fn empty() -> Self {
+        unsafe { mem::MaybeUninit::zeroed().assume_init() }
+    }
+
+    fn perm(&self) -> PteFlag {
+        let mut perm = PteFlag::empty();
+        if self.flags & ELF_PROG_FLAG_EXEC > 0 {
+            perm |= PteFlag::X;
+        }
+        if self.flags & ELF_PROG_FLAG_WRITE > 0 {
+            perm |= PteFlag::W;
+        }
+        perm
+    }
+}
 
 /// Load an elf executable into the process's user space
+/// and pass it the arguments in argv.
+/// Return argc, which ends up in a0 as the return value of exec.
 /// note: it can get the mut reference of a Proc,
 ///     because it will be valid until it calls exit itself
-/// TODO
-pub fn load(_p: &mut Proc, _path: &[u8]) -> Result<(), &'static str> {
-    // get relevant inode using path
+/// On failure, the process's old image is left untouched.
+/// Other threads sharing the old image keep running in it.
+pub fn load(p: &mut Proc, path: &[u8], argv: &[Option<Box<[u8; PGSIZE]>>])
+    -> Result<usize, &'static str>
+{
+    let pd = p.data.get_mut();
 
-    // check elf header, create new empty pagetable for user
+    // get relevant inode using path
+    LOG.begin_op();
+    let inode = match namei(path) {
+        Some(inode) => inode,
+        None => {
+            LOG.end_op();
+            return Err("exec: path not found")
+        }
+    };
+    let mut idata = inode.lock();
 
+    // check elf header, create new empty pagetable for user,
     // load each program section
+    let res = load_segments(&mut idata);
+    drop(idata);
+    drop(inode);
+    LOG.end_op();
+    let (mut pagetable, sz, entry) = res?;
 
-    // allocate space for user stack
-
+    // allocate space for user stack,
     // prepare content in the stack
+    let (sz, sp, argc) = match build_stack(&mut pagetable, sz, argv) {
+        Ok(ret) => ret,
+        Err(err) => {
+            UserSpace::free_pagetable(pagetable, sz);
+            return Err(err)
+        }
+    };
+
+    // switch to the new image
+    pd.set_space(Arc::new(SleepLock::new(UserSpace::new(pagetable, sz), "user space")))?;
 
     // update the process's info
+    // arguments to user main(argc, argv)
+    // argc is returned via the system call return value, which goes in a0
+    let tf = pd.tf_mut();
+    tf.a1 = sp;
+    tf.epc = entry;
+    tf.sp = sp;
+    let end = path.iter().position(|c| *c == 0).unwrap_or(path.len());
+    let last = path[..end].iter().rposition(|c| *c == b'/').map_or(0, |i| i + 1);
+    pd.set_name(&path[last..end]);
+    pd.set_alarm(0, 0);
+    p.excl.lock().signals.exec_reset();
+
+    Ok(argc)
+}
+
+/// Check the elf header and load each program section into a new pagetable.
+/// Return the pagetable, the size of the loaded image and the entry point.
+fn load_segments(idata: &mut InodeData)
+    -> Result<(Box<PageTable>, usize, usize), &'static str>
+{
+    let mut elf = ElfHeader::empty();
+    let elf_size = mem::size_of::<ElfHeader>() as u32;
+    match idata.read(Address::Kernel(&mut elf as *mut ElfHeader as *const u8), 0, elf_size) {
+        Ok(n) if n == elf_size => {},
+        _ => return Err("exec: cannot read elf header"),
+    }
+    if !elf.is_valid() {
+        return Err("exec: bad elf header")
+    }
+
+    let mut pagetable = UserSpace::make_pagetable()?;
+    let mut sz = 0;
+    let ph_size = mem::size_of::<ProgHeader>();
+    for i in 0..elf.phnum as usize {
+        let off = elf.phoff + i * ph_size;
+        let mut ph = ProgHeader::empty();
+        let res = match read_prog_header(idata, off, &mut ph) {
+            Ok(_) => load_segment(&mut pagetable, sz, idata, &ph),
+            Err(err) => Err(err),
+        };
+        match res {
+            Ok(new_sz) => sz = new_sz,
+            Err(err) => {
+                UserSpace::free_pagetable(pagetable, sz);
+                return Err(err)
+            }
+        }
+    }
+
+    Ok((pagetable, sz, elf.entry))
+}
+
+fn read_prog_header(idata: &mut InodeData, off: usize, ph: &mut ProgHeader)
+    -> Result<(), &'static str>
+{
+    let ph_size = mem::size_of::<ProgHeader>() as u32;
+    if off > u32::MAX as usize {
+        return Err("exec: bad program header offset")
+    }
+    match idata.read(Address::Kernel(ph as *mut ProgHeader as *const u8), off as u32, ph_size) {
+        Ok(n) if n == ph_size => Ok(()),
+        _ => Err("exec: cannot read program header"),
+    }
+}
+
+/// Map the program section ph into pagetable, which has sz bytes mapped,
+/// and read its content from the inode.
+/// Return the new size.
+fn load_segment(pagetable: &mut PageTable, sz: usize, idata: &mut InodeData, ph: &ProgHeader)
+    -> Result<usize, &'static str>
+{
+    if ph.ptype != ELF_PROG_LOAD {
+        return Ok(sz)
+    }
+    if ph.memsz < ph.filesz {
+        return Err("exec: memsz smaller than filesz")
+    }
+    let end = ph.vaddr.checked_add(ph.memsz).ok_or("exec: segment overflow")?;
+    if ph.vaddr % PGSIZE != 0 {
+        return Err("exec: segment not page aligned")
+    }
+    if ph.vaddr < sz {
+        return Err("exec: segments overlap")
+    }
+    if ph.off.checked_add(ph.filesz).map_or(true, |e| e > u32::MAX as usize) {
+        return Err("exec: bad segment offset")
+    }
+
+    let new_sz = pagetable.uvm_alloc(sz, end, ph.perm())?;
+
+    // copy the segment page by page, through the kernel's direct mapping
+    let mut i = 0;
+    while i < ph.filesz {
+        let pa = match pagetable.walk_addr(VirtAddr::try_from(ph.vaddr + i).unwrap()) {
+            Ok(pa) => pa,
+            Err(_) => panic!("exec: segment not mapped"),
+        };
+        let n = cmp::min(ph.filesz - i, PGSIZE) as u32;
+        match idata.read(Address::Kernel(pa.as_ptr()), (ph.off + i) as u32, n) {
+            Ok(read) if read == n => {},
+            _ => {
+                pagetable.uvm_dealloc(new_sz, sz);
+                return Err("exec: cannot read segment")
+            }
+        }
+        i += PGSIZE;
+    }
+
+    Ok(new_sz)
+}
+
+/// Allocate two pages at the next page boundary after sz.
+/// Use the second as the user stack and the first as an inaccessible guard.
+/// Return the new size, the stack pointer and argc.
+/// On failure, the stack pages are freed.
+fn build_stack(pagetable: &mut PageTable, sz: usize, argv: &[Option<Box<[u8; PGSIZE]>>])
+    -> Result<(usize, usize, usize), &'static str>
+{
+    let old_sz = pg_round_up(sz);
+    let sz = pagetable.uvm_alloc(old_sz, old_sz + 2 * PGSIZE, PteFlag::W)?;
+    pagetable.uvm_clear(sz - 2 * PGSIZE);
+
+    match push_args(pagetable, sz, argv) {
+        Ok((sp, argc)) => Ok((sz, sp, argc)),
+        Err(err) => {
+            pagetable.uvm_dealloc(sz, old_sz);
+            Err(err)
+        }
+    }
+}
+
+/// Push the argument strings and then the array of pointers to them
+/// onto the stack page right below sp.
+/// Return the new stack pointer and argc.
+fn push_args(pagetable: &mut PageTable, mut sp: usize, argv: &[Option<Box<[u8; PGSIZE]>>])
+    -> Result<(usize, usize), &'static str>
+{
+    let stack_base = sp - PGSIZE;
+
+    // push argument strings, prepare rest of stack in ustack.
+    let mut ustack: [usize; MAXARG + 1] = [0; MAXARG + 1];
+    let mut argc = 0;
+    for arg in argv.iter() {
+        let arg = match arg {
+            Some(arg) => arg,
+            None => break,
+        };
+        if argc >= MAXARG {
+            return Err("exec: too many arguments")
+        }
+        let len = arg.iter().position(|c| *c == 0).ok_or("exec: argument too long")? + 1;
+        if len > sp - stack_base {
+            return Err("exec: arguments too large")
+        }
+        sp -= len;
+        sp -= sp % 16; // riscv sp must be 16-byte aligned
+        if sp < stack_base {
+            return Err("exec: arguments too large")
+        }
+        pagetable.copy_out(arg.as_ptr(), sp, len)?;
+        ustack[argc] = sp;
+        argc += 1;
+    }
+    ustack[argc] = 0;
+
+    // push the array of argv[] pointers.
+    let len = (argc + 1) * mem::size_of::<usize>();
+    if len > sp - stack_base {
+        return Err("exec: arguments too large")
+    }
+    sp -= len;
+    sp -= sp % 16;
+    if sp < stack_base {
+        return Err("exec: arguments too large")
+    }
+    pagetable.copy_out(ustack.as_ptr() as *const u8, sp, len)?;
 
-    Ok(())
-}
\ No newline at end of file
+    Ok((sp, argc))
+}
@@ -0,0 +1,174 @@
+//! Fast user-space locking
+//!
+//! A futex is a user word. One in a private area is keyed by
+//! its address space and virtual address, so the threads sharing the space
+//! wait on the same futex, even after copy-on-write moves its page.
+//! One in a shared area, by MAP_SHARED or shmat, is keyed by its physical address,
+//! so the processes mapping the page wait on the same futex,
+//! and a reference to the page is held while a waiter is queued on it.
+//! The waiters are kept in wait queues hashed by key,
+//! so a wake only looks at the queue of its bucket.
+
+use array_macro::array;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr;
+
+use crate::mm::RawPage;
+use crate::spinlock::SpinLock;
+use crate::timer::{self, Expiry};
+use super::PROC_MANAGER;
+use super::proc::Proc;
+use super::space::UserSpace;
+
+/// Number of wait queues.
+const NBUCKET: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Key {
+    /// address space and virtual address of a word in a private area
+    Private(usize, usize),
+    /// physical address of a word in a shared area
+    Shared(usize),
+}
+
+impl Key {
+    /// Key of the user word at va, which must be present, in space.
+    /// Also return its physical address.
+    fn of(space: &UserSpace, va: usize) -> Result<(Self, usize), &'static str> {
+        let (pa, shared) = space.translate(va)?;
+        let key = if shared {
+            Key::Shared(pa)
+        } else {
+            Key::Private(space as *const UserSpace as usize, va)
+        };
+        Ok((key, pa))
+    }
+
+    fn queue(&self) -> &'static SpinLock<Vec<Waiter>> {
+        let hash = match *self {
+            Key::Private(space, va) => (space >> 12) ^ (va >> 2),
+            Key::Shared(pa) => pa >> 2,
+        };
+        &QUEUES[hash % NBUCKET]
+    }
+
+    /// Drop the page reference of a waiter taken out of its queue.
+    fn dequeued(&self) {
+        if let Key::Shared(pa) = *self {
+            unsafe { RawPage::put(pa); }
+        }
+    }
+}
+
+struct Waiter {
+    key: Key,
+    /// index of the process in the table
+    index: usize,
+    /// what the process sleeps on, unique to the waiter
+    channel: usize,
+}
+
+static QUEUES: [SpinLock<Vec<Waiter>>; NBUCKET] =
+    array![_ => SpinLock::new(Vec::new(), "futex"); NBUCKET];
+
+impl Proc {
+    /// Sleep while the user word at addr is val,
+    /// until woken up by futex_wake, or for at most timeout cycles of mtime.
+    pub fn futex_wait(&mut self, addr: usize, val: u32, timeout: Option<u64>)
+        -> Result<(), &'static str>
+    {
+        if addr % 4 != 0 {
+            return Err("futex: addr not aligned")
+        }
+        let pd = self.data.get_mut();
+        pd.lazy_map(addr, mem::size_of::<u32>())?;
+        let space = Arc::clone(pd.space());
+        let channel = self.wait_channel();
+
+        // the word is read with the address space locked, so its page stays,
+        // and with the queue locked, so a wake after changing it is not missed
+        let guard = space.lock();
+        let (key, pa) = Key::of(&guard, addr)?;
+        let mut queue = key.queue().lock();
+        if unsafe { ptr::read_volatile(pa as *const u32) } != val {
+            drop(queue);
+            return Err("futex: value changed")
+        }
+        if self.interrupted() {
+            drop(queue);
+            return Err("futex: interrupted")
+        }
+        let timer = match timeout {
+            Some(timeout) => {
+                let deadline = timer::now().saturating_add(timeout);
+                Some(timer::add_timer(deadline, Expiry::Wakeup(channel))?)
+            }
+            None => None,
+        };
+        if let Key::Shared(pa) = key {
+            RawPage::share(pa);
+        }
+        queue.push(Waiter {
+            key,
+            index: self.index(),
+            channel,
+        });
+        drop(guard);
+        self.sleep(channel, queue);
+
+        if let Some(id) = timer {
+            timer::cancel(id);
+        }
+        // still queued if not woken up by futex_wake
+        let mut queue = key.queue().lock();
+        let pos = queue.iter().position(|waiter| waiter.channel == channel);
+        if let Some(pos) = pos {
+            queue.remove(pos);
+        }
+        drop(queue);
+        match pos {
+            None => Ok(()),
+            Some(_) => {
+                key.dequeued();
+                if self.interrupted() {
+                    Err("futex: interrupted")
+                } else {
+                    Err("futex: timed out")
+                }
+            }
+        }
+    }
+
+    /// Wake up at most n processes waiting on the user word at addr,
+    /// in the order they wait.
+    /// Return the number woken up.
+    pub fn futex_wake(&mut self, addr: usize, n: usize) -> Result<usize, &'static str> {
+        if addr % 4 != 0 {
+            return Err("futex: addr not aligned")
+        }
+        let pd = self.data.get_mut();
+        pd.lazy_map(addr, mem::size_of::<u32>())?;
+        let (key, _) = Key::of(&pd.space().lock(), addr)?;
+
+        let mut queue = key.queue().lock();
+        let mut woken = 0;
+        while woken < n {
+            match queue.iter().position(|waiter| waiter.key == key) {
+                Some(pos) => {
+                    let waiter = queue.remove(pos);
+                    unsafe { PROC_MANAGER.wakeup_proc(waiter.index, waiter.channel); }
+                    woken += 1;
+                }
+                None => break,
+            }
+        }
+        drop(queue);
+        for _ in 0..woken {
+            key.dequeued();
+        }
+        Ok(woken)
+    }
+}
@@ -0,0 +1,311 @@
+//! Synchronous message-passing IPC
+//!
+//! An endpoint, created by endpoint_create as a file descriptor,
+//! is where senders and receivers rendezvous: send blocks until
+//! a receiver takes the message, and recv blocks until there is one.
+//! call sends a message and then blocks until the receiver replies to it,
+//! or the receiver exits or closes the endpoint, failing the call.
+//!
+//! A message is MSG_WORDS words passed in registers, a3 to a5 of the trapframe,
+//! along with up to MSGMAX bytes at a user buffer, a1 and a2,
+//! which is copied through the kernel.
+
+use array_macro::array;
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cmp;
+
+use crate::consts::{MSG_WORDS, MSGMAX, NENDPOINT, fs::NOFILE};
+use crate::spinlock::SpinLock;
+use super::PROC_MANAGER;
+use super::proc::Proc;
+
+pub struct Message {
+    words: [usize; MSG_WORDS],
+    data: Vec<u8>,
+}
+
+/// A process blocked sending a message.
+struct Sender {
+    pid: usize,
+    /// index of the process in the table
+    index: usize,
+    /// what the process sleeps on, unique to the sender
+    channel: usize,
+    /// waits for a reply after the message is taken
+    call: bool,
+    /// None while a receiver copies it out,
+    /// which puts it back if that fails
+    msg: Option<Message>,
+}
+
+/// A process blocked in call, whose message is taken by server.
+struct Caller {
+    pid: usize,
+    index: usize,
+    channel: usize,
+    /// pid of the receiver to reply
+    server: usize,
+    /// the endpoint the message is taken from
+    ep: usize,
+    /// the reply, or why there is none
+    reply: Option<Result<Message, &'static str>>,
+}
+
+/// All the IPC state is under one lock,
+/// so that a message moves from a queue to a reply atomically.
+struct Ipc {
+    /// blocked senders of each endpoint, None if the endpoint is free
+    queues: [Option<VecDeque<Sender>>; NENDPOINT],
+    callers: Vec<Caller>,
+}
+
+impl Ipc {
+    fn queue(&mut self, ep: usize) -> &mut VecDeque<Sender> {
+        self.queues[ep].as_mut().expect("ipc: endpoint freed")
+    }
+
+    /// Fail the calls taken by the process server that it has not replied to,
+    /// those from the endpoint ep, or all if None.
+    fn fail_callers(&mut self, server: usize, ep: Option<usize>, err: &'static str) {
+        for caller in self.callers.iter_mut() {
+            if caller.server == server && caller.reply.is_none()
+                && ep.map_or(true, |ep| caller.ep == ep)
+            {
+                caller.reply = Some(Err(err));
+                unsafe { PROC_MANAGER.wakeup_proc(caller.index, caller.channel); }
+            }
+        }
+    }
+
+    /// What the receivers of the endpoint sleep on.
+    fn channel(&self, ep: usize) -> usize {
+        &self.queues[ep] as *const _ as usize
+    }
+}
+
+static IPC: SpinLock<Ipc> = SpinLock::new(Ipc {
+    queues: array![_ => None; NENDPOINT],
+    callers: Vec::new(),
+}, "ipc");
+
+/// An IPC endpoint, kept open by a File.
+/// A process blocked on it holds a reference to the File,
+/// so there is no one blocked when it is dropped.
+pub struct Endpoint(usize);
+
+impl Endpoint {
+    pub fn new() -> Result<Self, &'static str> {
+        let mut ipc = IPC.lock();
+        let res = match ipc.queues.iter().position(|queue| queue.is_none()) {
+            Some(ep) => {
+                ipc.queues[ep] = Some(VecDeque::new());
+                Ok(Self(ep))
+            }
+            None => Err("endpoint_create: too many endpoints"),
+        };
+        drop(ipc);
+        res
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let queue = IPC.lock().queues[self.0].take();
+        drop(queue);
+    }
+}
+
+impl Proc {
+    /// Copy in the message to send from the syscall arguments,
+    /// the buffer at a1 of a2 bytes and the words in a3 to a5.
+    pub fn arg_msg(&mut self) -> Result<Message, &'static str> {
+        let buf = self.arg_raw(1);
+        let len = self.arg_raw(2);
+        if len > MSGMAX {
+            return Err("ipc: message too long")
+        }
+        let mut data = Vec::new();
+        data.resize(len, 0u8);
+        if len > 0 {
+            self.data.get_mut().copy_in(buf, data.as_mut_ptr(), len)?;
+        }
+        let mut words = [0; MSG_WORDS];
+        for (n, word) in words.iter_mut().enumerate() {
+            *word = self.arg_raw(3 + n);
+        }
+        Ok(Message { words, data })
+    }
+
+    /// Hand the received message to user space,
+    /// copying its data to the buffer at a1 of a2 bytes, truncated if longer,
+    /// and setting a2 to the number of bytes copied and a3 to a5 to the words.
+    pub fn put_msg(&mut self, msg: &Message) -> Result<(), &'static str> {
+        let buf = self.arg_raw(1);
+        let n = cmp::min(msg.data.len(), self.arg_raw(2));
+        let pd = self.data.get_mut();
+        if n > 0 {
+            pd.copy_out(msg.data.as_ptr(), buf, n)?;
+        }
+        let tf = pd.tf_mut();
+        tf.a2 = n;
+        tf.a3 = msg.words[0];
+        tf.a4 = msg.words[1];
+        tf.a5 = msg.words[2];
+        Ok(())
+    }
+
+    /// Send msg to the endpoint, blocking until a receiver takes it.
+    /// If call is true, also block until the receiver replies,
+    /// and return the reply.
+    pub fn ipc_send(&mut self, ep: &Endpoint, msg: Message, call: bool)
+        -> Result<Option<Message>, &'static str>
+    {
+        let pid = self.excl.lock().pid;
+        let channel = self.wait_channel();
+
+        let mut ipc = IPC.lock();
+        if self.interrupted() {
+            drop(ipc);
+            return Err("send: interrupted")
+        }
+        ipc.queue(ep.0).push_back(Sender {
+            pid,
+            index: self.index(),
+            channel,
+            call,
+            msg: Some(msg),
+        });
+        unsafe { PROC_MANAGER.wakeup(ipc.channel(ep.0)); }
+
+        loop {
+            self.sleep(channel, ipc);
+            ipc = IPC.lock();
+
+            // not taken by a receiver yet, or being copied out by one
+            let queue = ipc.queue(ep.0);
+            if let Some(pos) = queue.iter().position(|sender| sender.channel == channel) {
+                if queue[pos].msg.is_some() && self.interrupted() {
+                    queue.remove(pos);
+                    drop(ipc);
+                    return Err("send: interrupted")
+                }
+                continue
+            }
+            if !call {
+                drop(ipc);
+                return Ok(None)
+            }
+
+            // taken, and waiting for the reply
+            let pos = ipc.callers.iter().position(|caller| caller.channel == channel)
+                .expect("call: caller lost");
+            if ipc.callers[pos].reply.is_some() {
+                let caller = ipc.callers.remove(pos);
+                drop(ipc);
+                return caller.reply.unwrap().map(Some)
+            }
+            if self.interrupted() {
+                ipc.callers.remove(pos);
+                drop(ipc);
+                return Err("call: interrupted")
+            }
+        }
+    }
+
+    /// Receive a message from the endpoint, blocking until there is one,
+    /// and hand it to user space, see put_msg.
+    /// Return the pid of the sender.
+    /// The sender stays queued until the message is copied out,
+    /// so it is not lost if that fails.
+    pub fn ipc_recv(&mut self, ep: &Endpoint) -> Result<usize, &'static str> {
+        let server = self.excl.lock().pid;
+        let mut ipc = IPC.lock();
+        loop {
+            if let Some(sender) = ipc.queue(ep.0).iter_mut().find(|sender| sender.msg.is_some()) {
+                let msg = sender.msg.take().unwrap();
+                let (pid, channel) = (sender.pid, sender.channel);
+                drop(ipc);
+                let res = self.put_msg(&msg);
+
+                ipc = IPC.lock();
+                let queue = ipc.queue(ep.0);
+                let pos = queue.iter().position(|sender| sender.channel == channel)
+                    .expect("recv: sender lost");
+                if let Err(err) = res {
+                    let sender = &mut queue[pos];
+                    sender.msg = Some(msg);
+                    // let the sender notice if it is interrupted meanwhile,
+                    // and other receivers take the message
+                    unsafe {
+                        PROC_MANAGER.wakeup_proc(sender.index, channel);
+                        PROC_MANAGER.wakeup(ipc.channel(ep.0));
+                    }
+                    drop(ipc);
+                    return Err(err)
+                }
+                let sender = queue.remove(pos).unwrap();
+                if sender.call {
+                    ipc.callers.push(Caller {
+                        pid,
+                        index: sender.index,
+                        channel,
+                        server,
+                        ep: ep.0,
+                        reply: None,
+                    });
+                } else {
+                    unsafe { PROC_MANAGER.wakeup_proc(sender.index, channel); }
+                }
+                drop(ipc);
+                return Ok(pid)
+            }
+            if self.interrupted() {
+                drop(ipc);
+                return Err("recv: interrupted")
+            }
+            let channel = ipc.channel(ep.0);
+            self.sleep(channel, ipc);
+            ipc = IPC.lock();
+        }
+    }
+
+    /// Reply msg to the process pid,
+    /// which is blocked in call with a message received by this process.
+    pub fn ipc_reply(&mut self, pid: usize, msg: Message) -> Result<(), &'static str> {
+        let server = self.excl.lock().pid;
+        let mut ipc = IPC.lock();
+        let res = match ipc.callers.iter_mut()
+            .find(|caller| caller.pid == pid && caller.server == server && caller.reply.is_none())
+        {
+            Some(caller) => {
+                caller.reply = Some(Ok(msg));
+                unsafe { PROC_MANAGER.wakeup_proc(caller.index, caller.channel); }
+                Ok(())
+            }
+            None => Err("reply: no such caller"),
+        };
+        drop(ipc);
+        res
+    }
+
+    /// Fail the calls taken from the endpoint ep and not replied to yet,
+    /// once the process closes its last descriptor of ep.
+    pub fn ipc_closed(&mut self, ep: &Endpoint) {
+        let pd = self.data.get_mut();
+        let open = (0..NOFILE).filter_map(|fd| pd.get_file(fd))
+            .any(|file| file.as_endpoint().map_or(false, |other| other.0 == ep.0));
+        if !open {
+            let server = self.excl.lock().pid;
+            IPC.lock().fail_callers(server, Some(ep.0), "call: endpoint closed by the receiver");
+        }
+    }
+
+    /// Fail the calls taken by the process and not replied to yet,
+    /// as it is exiting.
+    pub fn ipc_exited(&mut self) {
+        let server = self.excl.lock().pid;
+        IPC.lock().fail_callers(server, None, "call: receiver exited");
+    }
+}
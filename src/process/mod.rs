@@ -1,15 +1,20 @@
 use array_macro::array;
 
 use core::convert::TryFrom;
+use core::mem;
 use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::consts::{NPROC, PGSIZE, TRAMPOLINE, fs::ROOTDEV};
+use crate::consts::signal::{NSIG, SIGCHLD};
 use crate::mm::{kvm_map, PhysAddr, PteFlag, VirtAddr, RawPage};
-use crate::spinlock::SpinLock;
+use crate::spinlock::{SpinLock, SpinLockGuard};
 use crate::trap::user_trap_ret;
 use crate::fs;
 
 pub use cpu::{CPU_MANAGER, CpuManager};
+pub use ipc::Endpoint;
+use cpu::ALL_HARTS;
 pub use cpu::{push_off, pop_off};
 
 mod context;
@@ -18,9 +23,16 @@ mod cpu;
 mod trapframe;
 mod syscall;
 mod elf;
+mod vma;
+mod signal;
+mod space;
+pub mod sched;
+mod futex;
+mod shm;
+mod ipc;
 
 use context::Context;
-use proc::{Proc, ProcState};
+use proc::{Proc, ProcExcl, ProcState};
 use trapframe::TrapFrame;
 
 // no lock to protect PROC_MANAGER, i.e.,
@@ -32,6 +44,8 @@ pub static mut PROC_MANAGER: ProcManager = ProcManager::new();
 
 pub struct ProcManager {
     table: [Proc; NPROC],// 进程表，最多64个进程
+    /// parents[i] is the index of the table[i]'s parent process
+    parents: SpinLock<[Option<usize>; NPROC]>,
     init_proc: usize,
     pid: SpinLock<usize>,
 }
@@ -39,7 +53,8 @@ pub struct ProcManager {
 impl ProcManager {
     const fn new() -> Self {
         Self {
-            table: array![_ => Proc::new(); NPROC],
+            table: array![i => Proc::new(i); NPROC],
+            parents: SpinLock::new([None; NPROC], "proc parents"),
             init_proc: 0,
             pid: SpinLock::new(0, "nextpid"),
         }
@@ -76,8 +91,9 @@ impl ProcManager {
 
     /// Look in the process table for an UNUSED proc.
     /// If found, initialize state required to run in the kernel,
-    /// and return with its ProcExcl held.
-    /// If there are no free procs, return None.
+    /// and return it in ALLOCATED state, without user memory,
+    /// which the caller then sets up.
+    /// If there are no free procs, or a memory allocation fails, return None.
     fn alloc_proc(&mut self) ->
         Option<&mut Proc>
     {
@@ -92,9 +108,14 @@ impl ProcManager {
                     let pd = p.data.get_mut();
 
                     // alloc trapframe
-                    unsafe { pd.set_tf(RawPage::new_zeroed() as *mut TrapFrame); }
+                    match unsafe { RawPage::try_new_zeroed() } {
+                        Ok(tf) => pd.set_tf(tf as *mut TrapFrame),
+                        Err(_) => {
+                            drop(guard);
+                            return None
+                        }
+                    }
 
-                    pd.proc_pagetable();
                     pd.init_context();
                     guard.pid = new_pid;
                     guard.state = ProcState::ALLOCATED;
@@ -109,27 +130,23 @@ impl ProcManager {
         None
     }
 
-    /// Look in the process table for an RUNNABLE proc,
-    /// set its state to ALLOCATED and return without the proc's lock held.
+    /// Take out the next RUNNABLE proc for the cpu id,
+    /// and return it without the proc's lock held.
     /// Typically used in each cpu's scheduler
-    fn alloc_runnable(&mut self) ->
+    fn alloc_runnable(&mut self, id: usize) ->
         Option<&mut Proc>
     {
-        for p in self.table.iter_mut() {
-            let mut guard = p.excl.lock();
-            match guard.state {
-                ProcState::RUNNABLE => {
-                    guard.state = ProcState::ALLOCATED;
-                    drop(guard);
-                    return Some(p)
-                },
-                _ => {
-                    drop(guard);
-                },
-            }
-        }
+        let i = unsafe { CPU_MANAGER.pick_runnable(id) }?;
+        Some(&mut self.table[i])
+    }
 
-        None
+    /// Make the process at index i RUNNABLE,
+    /// and queue it on this cpu's run queue,
+    /// or on another one if its affinity excludes this cpu.
+    /// Caller must hold its excl lock, which guard is from.
+    fn set_runnable(&self, i: usize, guard: &mut ProcExcl) {
+        guard.state = ProcState::RUNNABLE;
+        unsafe { CPU_MANAGER.enqueue(i, &mut guard.sched, self.table[i].affinity()); }
     }
 
     /// Set up first process
@@ -139,8 +156,143 @@ impl ProcManager {
         let p = self.alloc_proc()
             .expect("user_init: all process should be unused");
         p.user_init();
+        let p = &self.table[self.init_proc];
         let mut guard = p.excl.lock();
-        guard.state = ProcState::RUNNABLE;
+        self.set_runnable(self.init_proc, &mut guard);
+        drop(guard);
+    }
+
+    /// Record that the process at index parent is the parent of
+    /// the process at index child.
+    fn set_parent(&self, child: usize, parent: usize) {
+        let mut parents = self.parents.lock();
+        parents[child] = Some(parent);
+        drop(parents);
+    }
+
+    /// Pass the abandoned children of the process at index pi to init.
+    /// Threads become its ordinary children, since init only waits.
+    /// Caller must hold the parents lock.
+    fn reparent(&self, parents: &mut [Option<usize>; NPROC], pi: usize) {
+        for (i, child) in parents.iter_mut().enumerate() {
+            if *child == Some(pi) {
+                *child = Some(self.init_proc);
+                self.table[i].excl.lock().thread_stack = None;
+                self.wakeup(&self.table[self.init_proc] as *const Proc as usize);
+            }
+        }
+    }
+
+    /// Finish exiting the process at index pi,
+    /// whose open files and cwd are already released.
+    /// The process remains in the zombie state
+    /// until its parent calls wait().
+    fn exiting(&self, pi: usize, status: i32) -> ! {
+        let mut parents = self.parents.lock();
+
+        // Give any children to init.
+        self.reparent(&mut parents, pi);
+
+        // Parent might be sleeping in wait().
+        let parent = parents[pi].expect("exiting: process has no parent");
+        self.wakeup(&self.table[parent] as *const Proc as usize);
+
+        // SIGCHLD is only for child processes, not threads
+        let thread = self.table[pi].excl.lock().thread_stack.is_some();
+        if !thread {
+            let mut pguard = self.table[parent].excl.lock();
+            if pguard.post_signal(SIGCHLD) {
+                self.set_runnable(parent, &mut pguard);
+            }
+            drop(pguard);
+        }
+
+        let p = &self.table[pi];
+        let mut guard = p.excl.lock();
+        guard.exit_status = status;
+        guard.state = ProcState::ZOMBIE;
+        drop(parents);
+
+        // Jump into the scheduler, never to return.
+        unsafe {
+            let c = CPU_MANAGER.my_cpu_mut();
+            c.sched(guard, (*p.data.get()).get_context());
+        }
+        panic!("zombie exit");
+    }
+
+    /// Wait for a child of the process at index pi to exit and return its pid.
+    /// Only wait for the threads created by clone if threads is true,
+    /// otherwise only for the processes created by fork.
+    /// If addr is not zero, copy the child's exit status,
+    /// or the user stack given to clone for a thread, to it.
+    /// Return Err if the process has no such children.
+    fn waiting(&mut self, pi: usize, addr: usize, threads: bool) -> Result<usize, &'static str> {
+        let channel = &self.table[pi] as *const Proc as usize;
+        let mut parents = self.parents.lock();
+
+        loop {
+            // Scan through table looking for exited children.
+            let mut have_kids = false;
+            for i in 0..NPROC {
+                if parents[i] != Some(pi) {
+                    continue
+                }
+
+                let child = &mut self.table[i];
+                let guard = child.excl.lock();
+                if guard.thread_stack.is_some() != threads {
+                    drop(guard);
+                    continue
+                }
+                have_kids = true;
+                if guard.state != ProcState::ZOMBIE {
+                    drop(guard);
+                    continue
+                }
+
+                // Found one.
+                let pid = guard.pid;
+                let status = guard.exit_status;
+                let stack = guard.thread_stack.unwrap_or(0);
+                drop(guard);
+                // unlink the zombie while copying out, so no one else reaps it
+                parents[i] = None;
+                drop(parents);
+
+                // copy out with no spinlock held,
+                // since it might sleep on the user space's lock
+                if addr != 0 {
+                    let pd = unsafe { &mut *self.table[pi].data.get() };
+                    let res = if threads {
+                        pd.copy_out(&stack as *const usize as *const u8, addr, mem::size_of::<usize>())
+                    } else {
+                        pd.copy_out(&status as *const i32 as *const u8, addr, mem::size_of::<i32>())
+                    };
+                    if let Err(err) = res {
+                        // leave the zombie to be waited for again
+                        self.parents.lock()[i] = Some(pi);
+                        return Err(err)
+                    }
+                }
+                self.table[i].free();
+                return Ok(pid)
+            }
+
+            // No point waiting if we don't have any children.
+            if !have_kids {
+                drop(parents);
+                return Err("wait: no children")
+            }
+            if self.table[pi].interrupted() {
+                drop(parents);
+                return Err("wait: interrupted")
+            }
+
+            // Wait for a child to exit.
+            self.table[pi].sleep(channel, parents);
+            parents = self.parents.lock();
+        }
     }
 
     /// Check if the given process is the init_proc 
@@ -148,13 +300,87 @@ impl ProcManager {
         ptr::eq(&self.table[0], p)
     }
 
+    /// Send the signal sig to the process with the given pid.
+    /// If sig is zero, only check that the process exists.
+    /// A terminated victim won't exit until it tries to return
+    /// to user space (see user_trap in trap.rs),
+    /// or until it notices the killed flag in a sleep loop.
+    pub fn kill(&self, pid: usize, sig: usize) -> Result<(), &'static str> {
+        if sig >= NSIG {
+            return Err("kill: invalid signal")
+        }
+        let (i, mut guard) = self.find_pid(pid).ok_or("kill: no such process")?;
+        if sig != 0 && guard.state != ProcState::ZOMBIE && guard.post_signal(sig) {
+            self.set_runnable(i, &mut guard);
+        }
+        drop(guard);
+        Ok(())
+    }
+
+    /// Find the process with the given pid.
+    /// Return its index along with its locked ProcExcl.
+    fn find_pid(&self, pid: usize) -> Option<(usize, SpinLockGuard<'_, ProcExcl>)> {
+        for (i, p) in self.table.iter().enumerate() {
+            let guard = p.excl.lock();
+            if guard.pid == pid && guard.state != ProcState::UNUSED {
+                return Some((i, guard))
+            }
+            drop(guard);
+        }
+        None
+    }
+
+    /// Return the clock ticks the process pid has run for.
+    pub fn ticks(&self, pid: usize) -> Result<usize, &'static str> {
+        let (_, guard) = self.find_pid(pid).ok_or("getticks: no such process")?;
+        let ticks = guard.ticks;
+        drop(guard);
+        Ok(ticks)
+    }
+
+    /// Set the harts the process pid may run on, a bit per hart.
+    /// Bits beyond the harts are ignored.
+    /// If it is queued, it moves onto a hart in mask right away,
+    /// if it is running on a hart not in mask, it moves at its next yield.
+    pub fn set_affinity(&self, pid: usize, mask: usize) -> Result<(), &'static str> {
+        let mask = mask & ALL_HARTS;
+        if mask == 0 {
+            return Err("sched_setaffinity: no hart in mask")
+        }
+        let (i, mut guard) = self.find_pid(pid).ok_or("sched_setaffinity: no such process")?;
+        self.table[i].set_affinity(mask);
+        if guard.state == ProcState::RUNNABLE {
+            unsafe { CPU_MANAGER.requeue(i, &mut guard.sched, mask); }
+        }
+        drop(guard);
+        Ok(())
+    }
+
+    /// Return the harts the process pid may run on.
+    pub fn get_affinity(&self, pid: usize) -> Result<usize, &'static str> {
+        let (i, guard) = self.find_pid(pid).ok_or("sched_getaffinity: no such process")?;
+        drop(guard);
+        Ok(self.table[i].affinity())
+    }
+
+    /// Wake up the process at index i if it is sleeping on channel,
+    /// without looking through the whole table as wakeup does.
+    /// Must be called without any p->lock.
+    pub fn wakeup_proc(&self, i: usize, channel: usize) {
+        let mut guard = self.table[i].excl.lock();
+        if guard.state == ProcState::SLEEPING && guard.channel == channel {
+            self.set_runnable(i, &mut guard);
+        }
+        drop(guard);
+    }
+
     /// Wake up all processes sleeping on chan.
     /// Must be called without any p->lock.
     pub fn wakeup(&self, channel: usize) {
-        for p in self.table.iter() {
+        for (i, p) in self.table.iter().enumerate() {
             let mut guard = p.excl.lock();
             if guard.state == ProcState::SLEEPING && guard.channel == channel {
-                guard.state = ProcState::RUNNABLE;
+                self.set_runnable(i, &mut guard);
             }
             drop(guard);
         }
@@ -166,15 +392,24 @@ impl ProcManager {
 /// 
 /// Need to be handled carefully, because CPU use ra to jump here
 unsafe fn fork_ret() -> ! {
-    static mut FIRST: bool = true;
+    // Set by the first process to run, which initializes the file system.
+    static FS_CLAIMED: AtomicBool = AtomicBool::new(false);
+    // Set once the file system is initialized.
+    static FS_READY: AtomicBool = AtomicBool::new(false);
     
     // Still holding p->lock from scheduler
     CPU_MANAGER.my_proc().excl.unlock();
     
-    if FIRST {
-        // File system initialization
-        FIRST = false;
+    if !FS_CLAIMED.swap(true, Ordering::AcqRel) {
+        // File system initialization,
+        // which sleeps for disk reads, so it can not run with a lock held
         fs::init(ROOTDEV);
+        FS_READY.store(true, Ordering::Release);
+    } else {
+        // another hart may still be initializing the file system
+        while !FS_READY.load(Ordering::Acquire) {
+            CPU_MANAGER.my_proc().yielding();
+        }
     }
 
     user_trap_ret();
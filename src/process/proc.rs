@@ -1,19 +1,37 @@
+use array_macro::array;
+
 use alloc::boxed::Box;
-use core::convert::TryFrom;
+use alloc::sync::Arc;
+use core::mem;
 use core::option::Option;
 use core::ptr;
 use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::{consts::{PGSIZE, TRAMPOLINE, TRAPFRAME}, register::sstatus};
-use crate::mm::{PageTable, PhysAddr, PteFlag, VirtAddr};
+use crate::{consts::{PGSIZE, trapframe, fs::NOFILE}, register::sstatus};
+use crate::fs::{File, Inode, LOG, namei};
+use crate::mm::{RawPage, pg_round_down};
 use crate::register::{satp, sepc};
+use crate::sleeplock::SleepLock;
 use crate::spinlock::{SpinLock, SpinLockGuard};
 use crate::trap::user_trap;
 
-use super::{CpuManager, syscall::Syscall};
+use super::CpuManager;
+use super::syscall;
 use super::PROC_MANAGER;
-use super::cpu::CPU_MANAGER;
+use super::cpu::{CPU_MANAGER, ALL_HARTS};
 use super::{fork_ret, Context, TrapFrame};
+use super::sched::{Policy, SchedInfo, Scheduler};
+use super::signal::SigState;
+use super::space::{Fill, UserSpace};
+use super::vma::Vma;
+
+/// Return address of the function a thread starts at, which is never mapped,
+/// so a thread returning from it instead of calling exit faults and is killed.
+const THREAD_RETURN: usize = 0xffffffff;
+
+/// Count of the wait channels handed out by Proc::wait_channel.
+static WAIT_CHANNELS: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub enum ProcState {
@@ -22,6 +40,8 @@ pub enum ProcState {
     RUNNABLE,
     RUNNING,
     ALLOCATED,
+    /// stopped by a signal until SIGCONT
+    STOPPED,
     ZOMBIE,
 }
 
@@ -30,6 +50,15 @@ pub struct ProcExcl {
     pub state: ProcState,
     pub channel: usize,
     pub pid: usize,
+    pub exit_status: i32,
+    pub killed: bool,
+    pub signals: SigState,
+    /// the user stack given to clone, if the process is a thread
+    pub thread_stack: Option<usize>,
+    /// state of the scheduling policy
+    pub sched: SchedInfo,
+    /// clock ticks the process has run for
+    pub ticks: usize,
 }
 
 impl ProcExcl {
@@ -38,6 +67,12 @@ impl ProcExcl {
             state: ProcState::UNUSED,
             channel: 0,
             pid: 0,
+            exit_status: 0,
+            killed: false,
+            signals: SigState::new(),
+            thread_stack: None,
+            sched: Policy::INFO,
+            ticks: 0,
         }
     }
 }
@@ -47,22 +82,41 @@ impl ProcExcl {
 /// or initialed by other process(e.g. fork) with ProcExcl lock held
 pub struct ProcData {
     kstack: usize,
-    sz: usize,
-    pagetable: Option<Box<PageTable>>,
     tf: *mut TrapFrame,
+    /// where the trapframe is mapped in the user page table
+    tf_va: usize,
+    /// user memory, shared with the threads created by clone
+    space: Option<Arc<SleepLock<UserSpace>>>,
+    /// satp of the user page table
+    satp: usize,
     context: Context,
     name: [u8; 16],
+    open_files: [Option<Arc<File>>; NOFILE],
+    cwd: Option<Inode>,
+    /// alarm every alarm_interval ticks, zero if disabled
+    alarm_interval: usize,
+    alarm_handler: usize,
+    alarm_ticks: usize,
+    /// the interrupted trapframe while the alarm handler runs
+    alarm_tf: Option<Box<TrapFrame>>,
 }
 
 impl ProcData {
-    const fn new() -> Self {
+    const fn new(index: usize) -> Self {
         Self {
             kstack: 0,
-            sz: 0,
-            pagetable: None,
             tf: ptr::null_mut(),
+            tf_va: trapframe(index).as_usize(),
+            space: None,
+            satp: 0,
             context: Context::new(),
             name: [0; 16],
+            open_files: array![_ => None; NOFILE],
+            cwd: None,
+            alarm_interval: 0,
+            alarm_handler: 0,
+            alarm_ticks: 0,
+            alarm_tf: None,
         }
     }
 
@@ -71,32 +125,74 @@ impl ProcData {
         self.kstack = kstack;
     }
 
-    /// Allocate a new user pagetable for itself
-    /// and map trampoline code and trapframe
-    pub fn proc_pagetable(&mut self) {
-        extern "C" {
-            fn trampoline();
+    /// The user address space, which the process must have.
+    pub fn space(&self) -> &Arc<SleepLock<UserSpace>> {
+        self.space.as_ref().expect("process has no user space")
+    }
+
+    /// Start using the user address space,
+    /// and stop using the old one if any, see release_space.
+    /// On failure, the old one is left untouched.
+    pub fn set_space(&mut self, space: Arc<SleepLock<UserSpace>>) -> Result<(), &'static str> {
+        let mut guard = space.lock();
+        guard.enter(self.tf_va, self.tf as usize)?;
+        let satp = guard.pagetable_mut().as_satp();
+        drop(guard);
+
+        self.release_space();
+        self.space = Some(space);
+        self.satp = satp;
+        Ok(())
+    }
+
+    /// Stop using the user address space.
+    /// The last thread using it unmaps the mapped areas,
+    /// and frees the user memory.
+    pub fn release_space(&mut self) {
+        if let Some(space) = self.space.take() {
+            let write_backs = space.lock().leave(self.tf_va);
+            for write_back in write_backs {
+                if let Err(err) = write_back.write() {
+                    println!("mmap: write back failed: {}", err);
+                }
+            }
+            drop(space);
         }
+        self.satp = 0;
+    }
 
-        let mut pagetable = PageTable::uvm_create();
-        pagetable
-            .map_pages(
-                VirtAddr::from(TRAMPOLINE),
-                PGSIZE,
-                PhysAddr::try_from(trampoline as usize).unwrap(),
-                PteFlag::R | PteFlag::X,
-            )
-            .expect("user proc table mapping trampoline");
-        pagetable
-            .map_pages(
-                VirtAddr::from(TRAPFRAME),
-                PGSIZE,
-                PhysAddr::try_from(self.tf as usize).unwrap(),
-                PteFlag::R | PteFlag::W,
-            )
-            .expect("user proc table mapping trapframe");
-
-        self.pagetable = Some(pagetable);
+    /// Size of the process's user memory.
+    pub fn get_sz(&self) -> usize {
+        self.space().lock().get_sz()
+    }
+
+    /// Grow or shrink user memory by n bytes.
+    /// Return the old size, which is the start of the new memory if growing.
+    pub fn grow(&mut self, n: i32) -> Result<usize, &'static str> {
+        self.space().lock().grow(n)
+    }
+
+    /// Set the process's name, which is truncated if too long.
+    pub fn set_name(&mut self, name: &[u8]) {
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        let len = core::cmp::min(len, self.name.len() - 1);
+        self.name[..len].copy_from_slice(&name[..len]);
+        for c in self.name[len..].iter_mut() {
+            *c = 0;
+        }
+    }
+
+    /// Free the process's trapframe,
+    /// and reset the rest for the next use.
+    /// Open files, cwd and the user space should be released beforehand.
+    pub fn cleanup(&mut self) {
+        assert!(self.space.is_none(), "cleanup: user space not released");
+        if !self.tf.is_null() {
+            unsafe { RawPage::from_raw_and_drop(self.tf as usize); }
+            self.tf = ptr::null_mut();
+        }
+        self.name = [0; 16];
+        self.set_alarm(0, 0);
     }
 
     /// Set trapframe
@@ -104,6 +200,16 @@ impl ProcData {
         self.tf = tf;
     }
 
+    /// Return the mutable reference of the process's trapframe
+    pub fn tf_mut(&mut self) -> &mut TrapFrame {
+        unsafe { &mut *self.tf }
+    }
+
+    /// Where the trapframe is mapped in the user page table
+    pub fn trapframe_va(&self) -> usize {
+        self.tf_va
+    }
+
     /// Init the context of the process after it is created
     /// Set its return address to fork_ret,
     /// which start to return to user space.
@@ -132,7 +238,160 @@ impl ProcData {
         // restore the user pc previously stored in sepc
         sepc::write(tf.epc);
 
-        self.pagetable.as_ref().unwrap().as_satp()
+        self.satp
+    }
+
+    /// Copy count bytes from kernel's src to user's virtual address dst.
+    pub fn copy_out(&mut self, src: *const u8, dst: usize, count: usize) -> Result<(), &'static str> {
+        self.lazy_map(dst, count)?;
+        self.space().lock().pagetable_mut().copy_out(src, dst, count)
+    }
+
+    /// Copy count bytes from user's virtual address src to kernel's dst.
+    pub fn copy_in(&mut self, src: usize, dst: *mut u8, count: usize) -> Result<(), &'static str> {
+        self.lazy_map(src, count)?;
+        self.space().lock().pagetable_mut().copy_in(src, dst, count)
+    }
+
+    /// Copy a null-terminated string from user's virtual address src
+    /// to kernel's dst, which is at most dst.len() bytes long.
+    pub fn copy_in_str(&mut self, src: usize, dst: &mut [u8]) -> Result<(), &'static str> {
+        self.lazy_map(src, dst.len())?;
+        self.space().lock().pagetable_mut().copy_in_str(src, dst)
+    }
+
+    /// Allocate the not-yet-allocated user pages in [va, va+count),
+    /// including the mapped ones, so the kernel can copy in or out of them.
+    /// Pages in neither are left for the copy to fail on.
+    pub fn lazy_map(&mut self, va: usize, count: usize) -> Result<(), &'static str> {
+        let end = va.saturating_add(count);
+        let mut a = pg_round_down(va);
+        while a < end {
+            let fill = self.space().lock().lazy(a)?;
+            match fill {
+                Fill::Done => {},
+                Fill::File(vma) => self.fill_page(&vma, a)?,
+                Fill::Outside => break,
+            }
+            a += PGSIZE;
+        }
+        Ok(())
+    }
+
+    /// Handle a user page fault at virtual address va.
+    pub fn page_fault(&mut self, va: usize, store: bool) -> Result<(), &'static str> {
+        let fill = self.space().lock().fault(va, store)?;
+        match fill {
+            Fill::File(vma) => self.fill_page(&vma, va),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the page at va of the file-backed area vma,
+    /// with the address space unlocked, and then map it.
+    fn fill_page(&mut self, vma: &Vma, va: usize) -> Result<(), &'static str> {
+        let mem = vma.fill(va)?;
+        self.space().lock().install(va, mem)
+    }
+
+    /// Map len bytes of the file at offset, or anonymous memory if file is None.
+    /// Return the start address of the new area.
+    pub fn mmap(&mut self, len: usize, prot: i32, shared: bool,
+        file: Option<Arc<File>>, offset: usize) -> Result<usize, &'static str>
+    {
+        self.space().lock().mmap(len, prot, shared, file, offset)
+    }
+
+    /// Unmap [addr, addr+len) from a mapped area,
+    /// writing back its dirty pages if it is a shared file mapping.
+    pub fn munmap(&mut self, addr: usize, len: usize) -> Result<(), &'static str> {
+        let (write_back, vma) = self.space().lock().munmap(addr, len)?;
+        let res = match write_back {
+            Some(write_back) => write_back.write(),
+            None => Ok(()),
+        };
+        drop(vma);
+        res
+    }
+
+    /// Call the user handler at handler every interval ticks.
+    /// Disable the alarm if interval is zero.
+    pub fn set_alarm(&mut self, interval: usize, handler: usize) {
+        self.alarm_interval = interval;
+        self.alarm_handler = handler;
+        self.alarm_ticks = 0;
+        self.alarm_tf = None;
+    }
+
+    /// Called at each timer interrupt from user space.
+    /// If the alarm goes off, save the interrupted trapframe,
+    /// and divert the return to user space to the handler.
+    /// The handler is not reentered until it calls sigreturn.
+    pub fn alarm_tick(&mut self) {
+        if self.alarm_interval == 0 || self.alarm_tf.is_some() {
+            return
+        }
+        self.alarm_ticks += 1;
+        if self.alarm_ticks < self.alarm_interval {
+            return
+        }
+        self.alarm_ticks = 0;
+        let handler = self.alarm_handler;
+        let tf = self.tf_mut();
+        let saved = Box::new(*tf);
+        tf.epc = handler;
+        self.alarm_tf = Some(saved);
+    }
+
+    /// Return from the alarm handler,
+    /// by restoring the interrupted trapframe.
+    /// Return the interrupted a0, so the syscall return does not clobber it.
+    pub fn alarm_return(&mut self) -> Result<usize, &'static str> {
+        let saved = self.alarm_tf.take().ok_or("sigreturn: not in an alarm handler")?;
+        let tf = self.tf_mut();
+        *tf = *saved;
+        Ok(tf.a0)
+    }
+
+    /// Get another reference to the current working directory.
+    pub fn cwd_dup(&self) -> Inode {
+        self.cwd.as_ref().expect("process has no cwd").clone()
+    }
+
+    /// Replace the current working directory,
+    /// return the old one.
+    /// Must be called inside a transaction,
+    /// since the old one might be dropped by the caller.
+    pub fn set_cwd(&mut self, cwd: Inode) -> Option<Inode> {
+        self.cwd.replace(cwd)
+    }
+
+    /// Get the open file of the file descriptor fd.
+    pub fn get_file(&self, fd: usize) -> Option<&Arc<File>> {
+        match self.open_files.get(fd) {
+            Some(Some(file)) => Some(file),
+            _ => None,
+        }
+    }
+
+    /// Allocate a file descriptor for the given file.
+    /// Return the file back if there is no free descriptor.
+    pub fn alloc_fd(&mut self, file: Arc<File>) -> Result<usize, Arc<File>> {
+        match self.open_files.iter().position(|f| f.is_none()) {
+            Some(fd) => {
+                self.open_files[fd] = Some(file);
+                Ok(fd)
+            }
+            None => Err(file),
+        }
+    }
+
+    /// Take out the open file of the file descriptor fd.
+    pub fn take_file(&mut self, fd: usize) -> Option<Arc<File>> {
+        match self.open_files.get_mut(fd) {
+            Some(file) => file.take(),
+            None => None,
+        }
     }
 }
 
@@ -143,20 +402,49 @@ impl ProcData {
 /// but then if it is interrupted and get killed, so it need to
 /// clean its ProcData, so UnsafeCell is better.
 pub struct Proc {
+    /// index into the process table
+    index: usize,
     pub excl: SpinLock<ProcExcl>,
     pub data: UnsafeCell<ProcData>,
-    killed: bool,
+    /// harts the process may run on, a bit per hart,
+    /// written with excl held, but read by the scheduler of any hart without it
+    affinity: AtomicUsize,
 }
 
 impl Proc {
-    pub const fn new() -> Self {
+    pub const fn new(index: usize) -> Self {
         Self {
+            index,
             excl: SpinLock::new(ProcExcl::new(), "ProcExcl"),
-            data: UnsafeCell::new(ProcData::new()),
-            killed: false,
+            data: UnsafeCell::new(ProcData::new(index)),
+            affinity: AtomicUsize::new(ALL_HARTS),
         }
     }
 
+    /// Index into the process table.
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    #[inline]
+    pub fn affinity(&self) -> usize {
+        self.affinity.load(Ordering::Relaxed)
+    }
+
+    /// Set the harts the process may run on.
+    /// Caller must hold its excl lock.
+    #[inline]
+    pub fn set_affinity(&self, mask: usize) {
+        self.affinity.store(mask, Ordering::Relaxed);
+    }
+
+    /// Check if the process may run on the hart id.
+    #[inline]
+    pub fn allowed_on(&self, id: usize) -> bool {
+        self.affinity() & (1 << id) != 0
+    }
+
     /// Called by ProcManager's user_init,
     /// Only be called once for the first user process
     /// TODO - copy user code and sth else
@@ -164,8 +452,11 @@ impl Proc {
         let pd = self.data.get_mut();
 
         // map initcode in user pagetable
-        pd.pagetable.as_mut().unwrap().uvm_init(&INITCODE);
-        pd.sz = PGSIZE;
+        let mut space = UserSpace::empty().expect("user_init: no memory for user space");
+        space.pagetable_mut().uvm_init(&INITCODE);
+        space.set_sz(PGSIZE);
+        pd.set_space(Arc::new(SleepLock::new(space, "user space")))
+            .expect("user_init: cannot map trapframe");
 
         // prepare return pc and stack pointer
         let tf = unsafe { &mut *pd.tf };
@@ -181,34 +472,190 @@ impl Proc {
             );
         }
 
-        // TODO - p->cwd = namei("/");
+        pd.cwd = Some(namei(b"/").expect("user_init: root directory not found"));
+    }
+
+    /// Create a new process, copying the parent, i.e., self.
+    /// Set up the child's trapframe to return as if from fork() system call.
+    /// Return the child's pid.
+    pub fn fork(&mut self) -> Result<usize, &'static str> {
+        let pdata = self.data.get_mut();
+        let child = unsafe { PROC_MANAGER.alloc_proc() }
+            .ok_or("fork: no free process")?;
+        let cdata = child.data.get_mut();
+
+        // copy user memory and the mapped areas from parent to child.
+        let mut space = match UserSpace::empty() {
+            Ok(space) => space,
+            Err(err) => {
+                child.free();
+                return Err(err)
+            }
+        };
+        let res = pdata.space().lock().copy(&mut space);
+        if let Err(err) = res.and_then(|_| cdata.set_space(Arc::new(SleepLock::new(space, "user space")))) {
+            child.free();
+            return Err(err)
+        }
+
+        // copy saved user registers,
+        // and cause fork to return 0 in the child.
+        unsafe { ptr::copy_nonoverlapping(pdata.tf, cdata.tf, 1); }
+        cdata.tf_mut().a0 = 0;
+
+        // increment reference counts on open file descriptors.
+        cdata.open_files.clone_from(&pdata.open_files);
+        cdata.cwd = Some(pdata.cwd_dup());
+
+        cdata.name.copy_from_slice(&pdata.name);
+
+        // inherit the signal actions and mask.
+        let pexcl = self.excl.lock();
+        let signals = pexcl.signals.fork_copy();
+        let sched = Policy::inherit(&pexcl.sched);
+        drop(pexcl);
+
+        let cpid = child.excl.lock().pid;
+        unsafe { PROC_MANAGER.set_parent(child.index, self.index); }
+
+        let mut cexcl = child.excl.lock();
+        cexcl.signals = signals;
+        cexcl.sched = sched;
+        child.affinity.store(self.affinity(), Ordering::Relaxed);
+        unsafe { PROC_MANAGER.set_runnable(child.index, &mut cexcl); }
+        drop(cexcl);
+
+        Ok(cpid)
+    }
+
+    /// Create a thread sharing the user memory with the current process.
+    /// It starts at func on the user stack whose top is stack,
+    /// with arg as the argument, and should call exit instead of returning.
+    /// The open files are copied as in fork.
+    /// Return the thread's pid.
+    pub fn clone(&mut self, func: usize, stack: usize, arg: usize) -> Result<usize, &'static str> {
+        if stack % 16 != 0 {
+            return Err("clone: stack not aligned")
+        }
+        let pdata = self.data.get_mut();
+        let child = unsafe { PROC_MANAGER.alloc_proc() }
+            .ok_or("clone: no free process")?;
+        let cdata = child.data.get_mut();
+
+        if let Err(err) = cdata.set_space(Arc::clone(pdata.space())) {
+            child.free();
+            return Err(err)
+        }
+
+        // copy saved user registers,
+        // and start at func with a return address to fault at.
+        unsafe { ptr::copy_nonoverlapping(pdata.tf, cdata.tf, 1); }
+        let tf = cdata.tf_mut();
+        tf.epc = func;
+        tf.sp = stack;
+        tf.a0 = arg;
+        tf.ra = THREAD_RETURN;
+
+        cdata.open_files.clone_from(&pdata.open_files);
+        cdata.cwd = Some(pdata.cwd_dup());
+        cdata.name.copy_from_slice(&pdata.name);
+
+        let pexcl = self.excl.lock();
+        let signals = pexcl.signals.fork_copy();
+        let sched = Policy::inherit(&pexcl.sched);
+        drop(pexcl);
+
+        let cpid = child.excl.lock().pid;
+        unsafe { PROC_MANAGER.set_parent(child.index, self.index); }
+
+        let mut cexcl = child.excl.lock();
+        cexcl.signals = signals;
+        cexcl.sched = sched;
+        child.affinity.store(self.affinity(), Ordering::Relaxed);
+        cexcl.thread_stack = Some(stack);
+        unsafe { PROC_MANAGER.set_runnable(child.index, &mut cexcl); }
+        drop(cexcl);
+
+        Ok(cpid)
+    }
+
+    /// Free the process's data, including its share of the user memory,
+    /// and mark it unused.
+    /// The process must not be running, e.g., a zombie or a half-made child.
+    pub fn free(&mut self) {
+        self.data.get_mut().release_space();
+        let mut guard = self.excl.lock();
+        self.data.get_mut().cleanup();
+        guard.pid = 0;
+        guard.channel = 0;
+        guard.exit_status = 0;
+        guard.killed = false;
+        guard.signals = SigState::new();
+        guard.thread_stack = None;
+        guard.sched = Policy::INFO;
+        guard.ticks = 0;
+        self.set_affinity(ALL_HARTS);
+        guard.state = ProcState::UNUSED;
+        drop(guard);
     }
 
     /// Exit the current process. No return.
-    pub fn exit(&mut self, status: isize) {
+    /// An exited process remains in the zombie state
+    /// until its parent calls wait().
+    pub fn exit(&mut self, status: i32) -> ! {
         if unsafe { PROC_MANAGER.is_init_proc(&self) } {
             panic!("init_proc exiting");
         }
 
-        panic!("exit: TODO, status={}", status);
+        // Leave the user memory and close all open files.
+        let pd = self.data.get_mut();
+        pd.release_space();
+        for file in pd.open_files.iter_mut() {
+            drop(file.take());
+        }
+
+        LOG.begin_op();
+        drop(pd.cwd.take());
+        LOG.end_op();
+
+        self.ipc_exited();
+        unsafe { PROC_MANAGER.exiting(self.index, status) }
+    }
+
+    /// Wait for a child process to exit and return its pid.
+    /// Copy the child's exit status to addr if it is not zero.
+    pub fn wait(&mut self, addr: usize) -> Result<usize, &'static str> {
+        unsafe { PROC_MANAGER.waiting(self.index, addr, false) }
+    }
+
+    /// Wait for a thread created by clone to exit and return its pid.
+    /// Copy the user stack given to clone to addr if it is not zero,
+    /// so the caller can free it.
+    pub fn join(&mut self, addr: usize) -> Result<usize, &'static str> {
+        unsafe { PROC_MANAGER.waiting(self.index, addr, true) }
+    }
+
+    /// Check whether the process has been killed.
+    pub fn killed(&self) -> bool {
+        self.excl.lock().killed
+    }
+
+    /// Check whether the process has been killed,
+    /// or has a caught signal to deliver.
+    /// Sleep loops should call this and bail out if true.
+    pub fn interrupted(&self) -> bool {
+        let guard = self.excl.lock();
+        guard.killed || guard.signals.interrupting()
     }
 
     /// Abondon current process if
     /// the killed flag is true
-    pub fn check_abondon(&mut self, status: isize) {
-        if self.killed {
+    pub fn check_abondon(&mut self, status: i32) {
+        if self.killed() {
             self.exit(status);
         }
     }
 
-    /// Abondon current process by:
-    /// 1. setting its killed flag to true
-    /// 2. and then exit
-    pub fn abondon(&mut self, status: isize) {
-        self.killed = true;
-        self.exit(status);
-    }
-
     /// Handle system call
     /// It may be interrrupted in the procedure of syscall
     pub fn syscall(&mut self) {
@@ -217,25 +664,50 @@ impl Proc {
         let tf = unsafe { &mut *self.data.get_mut().tf };
         let a7 = tf.a7;
         tf.admit_ecall();
-        tf.a0 = match a7 {
-            7 => self.sys_exec(),
-            _ => {
-                panic!("unknown syscall num: {}", a7);
-            }
+        tf.a0 = match syscall::dispatch(self, a7) {
+            Ok(ret) => ret,
+            Err(_) => usize::MAX,
         };
     }
 
+    /// Charge a clock tick to the running process.
+    /// Return true if it should give up the cpu,
+    /// which it also does to move off a hart not in its affinity.
+    pub fn clock_tick(&self) -> bool {
+        let mut guard = self.excl.lock();
+        guard.ticks += 1;
+        let preempt = unsafe { CPU_MANAGER.sched_tick(self.index, &mut guard.sched) }
+            || !self.allowed_on(unsafe { CpuManager::cpu_id() });
+        drop(guard);
+        preempt
+    }
+
+    /// Set the tickets of the process for a proportional-share policy.
+    pub fn set_tickets(&self, tickets: usize) -> Result<(), &'static str> {
+        let mut guard = self.excl.lock();
+        let ret = Policy::set_tickets(&mut guard.sched, tickets);
+        drop(guard);
+        ret
+    }
+
     /// Give up the current runing process in this cpu
     /// Change the name to yielding, because `yield` is a key word
     pub fn yielding(&mut self) {
         let mut guard = self.excl.lock();
         assert_eq!(guard.state, ProcState::RUNNING);
-        guard.state = ProcState::RUNNABLE;
+        unsafe { PROC_MANAGER.set_runnable(self.index, &mut guard); }
         guard = unsafe { CPU_MANAGER.my_cpu_mut().sched(guard,
             &mut self.data.get_mut().context as *mut _) };
         drop(guard);
     }
 
+    /// Return a wait channel unique to one sleep of the process,
+    /// for wakers that wake it alone, e.g., by wakeup_proc or a timer.
+    /// The top bit is set, so it is never an address others sleep on.
+    pub fn wait_channel(&self) -> usize {
+        WAIT_CHANNELS.fetch_add(1, Ordering::Relaxed) | !(usize::MAX >> 1)
+    }
+
     /// Atomically release a spinlock and sleep on chan.
     /// The passed-in guard should not the proc's guard,
     /// otherwise it will deadlock(because it acquires proc's lock first).
@@ -291,9 +763,48 @@ impl Proc {
 
     pub fn arg_str(&self, n: usize, buf: &mut [u8]) -> Result<(), &'static str> {
         let addr: usize = self.arg_raw(n);
-        let pagetable = unsafe { (*self.data.get()).pagetable.as_ref().unwrap() };
-        pagetable.copy_in_str(addr, buf)?;
-        Ok(())
+        self.fetch_str(addr, buf)
+    }
+
+    /// Fetch a usize at addr from the current process.
+    pub fn fetch_addr(&self, addr: usize) -> Result<usize, &'static str> {
+        let pd = unsafe { &mut *self.data.get() };
+        let sz = pd.get_sz();
+        if addr >= sz || addr + mem::size_of::<usize>() > sz {
+            return Err("fetch_addr: addr out of range")
+        }
+        let mut ret: usize = 0;
+        pd.copy_in(addr, &mut ret as *mut usize as *mut u8, mem::size_of::<usize>())?;
+        Ok(ret)
+    }
+
+    /// Fetch the null-terminated string at addr from the current process.
+    pub fn fetch_str(&self, addr: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+        unsafe { (*self.data.get()).copy_in_str(addr, buf) }
+    }
+
+    pub fn arg_i32(&self, n: usize) -> i32 {
+        self.arg_raw(n) as i32
+    }
+
+    /// Fetch the n-th argument as a pid,
+    /// where 0 stands for the calling process.
+    pub fn arg_pid(&self, n: usize) -> Result<usize, &'static str> {
+        match self.arg_i32(n) {
+            0 => Ok(self.excl.lock().pid),
+            pid if pid > 0 => Ok(pid as usize),
+            _ => Err("negative pid"),
+        }
+    }
+
+    /// Fetch the n-th argument as a file descriptor,
+    /// and check that it refers to an open file.
+    pub fn arg_fd(&self, n: usize) -> Result<usize, &'static str> {
+        let fd = self.arg_raw(n);
+        match unsafe { (*self.data.get()).get_file(fd) } {
+            Some(_) => Ok(fd),
+            None => Err("invalid file descriptor"),
+        }
     }
 }
 
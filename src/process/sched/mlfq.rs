@@ -0,0 +1,132 @@
+//! Multi-level feedback queue scheduling
+
+use array_macro::array;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{RunQueue, Scheduler};
+
+/// Number of priority levels, 0 is the highest.
+const NLEVEL: usize = 3;
+
+/// Clock ticks a process can run at each level before being demoted.
+const SLICE: [usize; NLEVEL] = [1, 2, 4];
+
+/// Clock ticks between two boosts of every process to the top level,
+/// so CPU hogs at the bottom do not starve.
+const BOOST_INTERVAL: usize = 100;
+
+/// Clock ticks since the last boost.
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+/// Number of boosts so far.
+/// Each run queue and each process catch up with it lazily.
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Per-process state.
+#[derive(Clone, Copy)]
+pub struct Level {
+    level: usize,
+    /// ticks used at the current level
+    used: usize,
+    /// the boost this state is up to date with
+    epoch: usize,
+}
+
+impl Level {
+    /// Move the process to the top level if a boost happened since.
+    fn refresh(&mut self, epoch: usize) {
+        if self.epoch != epoch {
+            self.level = 0;
+            self.used = 0;
+            self.epoch = epoch;
+        }
+    }
+}
+
+/// Always run a process from the highest non-empty level.
+/// A process using up its time slice is demoted one level,
+/// while one giving up the cpu earlier, e.g., to wait for input, stays.
+pub struct Mlfq {
+    queues: [RunQueue; NLEVEL],
+    /// the boost the queues are up to date with
+    epoch: usize,
+}
+
+impl Mlfq {
+    pub const fn new() -> Self {
+        Self {
+            queues: array![_ => RunQueue::new(); NLEVEL],
+            epoch: 0,
+        }
+    }
+
+    /// Move every queued process to the top level if a boost happened since.
+    fn refresh(&mut self) {
+        let epoch = EPOCH.load(Ordering::Relaxed);
+        if self.epoch == epoch {
+            return
+        }
+        for level in 1..NLEVEL {
+            while let Some(i) = self.queues[level].pop_front() {
+                self.queues[0].push_back(i);
+            }
+        }
+        self.epoch = epoch;
+    }
+}
+
+impl Scheduler for Mlfq {
+    type Info = Level;
+
+    const INFO: Level = Level {
+        level: 0,
+        used: 0,
+        epoch: 0,
+    };
+
+    fn enqueue(&mut self, i: usize, info: &mut Level) {
+        self.refresh();
+        info.refresh(self.epoch);
+        self.queues[info.level].push_back(i);
+    }
+
+    fn pick<F: Fn(usize) -> bool>(&mut self, allowed: F) -> Option<usize> {
+        self.refresh();
+        self.queues.iter_mut().find_map(|queue| queue.take(&allowed))
+    }
+
+    fn remove(&mut self, i: usize, _info: &mut Level) -> bool {
+        self.queues.iter_mut().any(|queue| queue.take(|j| j == i).is_some())
+    }
+
+    fn any<F: Fn(usize) -> bool>(&self, allowed: F) -> bool {
+        self.queues.iter().any(|queue| queue.any(&allowed))
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    fn tick(&mut self, _i: usize, info: &mut Level) -> bool {
+        self.refresh();
+        info.refresh(self.epoch);
+        let level = info.level;
+        info.used += 1;
+        if info.used >= SLICE[level] {
+            if level + 1 < NLEVEL {
+                info.level = level + 1;
+            }
+            info.used = 0;
+            return true
+        }
+        // preempted by a process of higher priority
+        self.queues[..level].iter().any(|queue| !queue.is_empty())
+    }
+
+    fn clock() {
+        if TICKS.fetch_add(1, Ordering::Relaxed) + 1 >= BOOST_INTERVAL {
+            TICKS.store(0, Ordering::Relaxed);
+            EPOCH.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
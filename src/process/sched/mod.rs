@@ -0,0 +1,136 @@
+//! Scheduling policies
+//!
+//! A policy keeps the RUNNABLE processes, by their slots in the process table,
+//! and decides which one runs next and when the running one is preempted.
+//! Each cpu has its own instance as its run queue, see Cpu in cpu.rs,
+//! while the per-process state of the policy is kept in ProcExcl,
+//! so that it moves along with a process stolen by another cpu.
+//! The policy in use is chosen at compile time by cargo feature:
+//! round robin by default, "sched_mlfq" for a multi-level feedback queue,
+//! or "sched_stride" for proportional-share stride scheduling.
+
+use crate::consts::NPROC;
+
+mod rr;
+mod mlfq;
+pub mod stride;
+
+#[cfg(all(feature = "sched_mlfq", feature = "sched_stride"))]
+compile_error!("only one of the sched_* features can be enabled");
+
+#[cfg(not(any(feature = "sched_mlfq", feature = "sched_stride")))]
+pub use rr::RoundRobin as Policy;
+#[cfg(feature = "sched_mlfq")]
+pub use mlfq::Mlfq as Policy;
+#[cfg(feature = "sched_stride")]
+pub use stride::Stride as Policy;
+
+/// Per-process state of the policy in use.
+pub type SchedInfo = <Policy as Scheduler>::Info;
+
+pub trait Scheduler {
+    /// Per-process state, guarded by the process's excl lock.
+    type Info: Copy;
+
+    /// The state of a newly allocated process.
+    const INFO: Self::Info;
+
+    /// The state of a child created by fork or clone from parent.
+    fn inherit(_parent: &Self::Info) -> Self::Info {
+        Self::INFO
+    }
+
+    /// Set the tickets of a process, for proportional-share policies.
+    fn set_tickets(_info: &mut Self::Info, _tickets: usize) -> Result<(), &'static str> {
+        Err("settickets: not supported by the scheduling policy")
+    }
+
+    /// Queue the process at slot i, which just became RUNNABLE.
+    /// Each RUNNABLE process is queued exactly once, in one of the cpus.
+    fn enqueue(&mut self, i: usize, info: &mut Self::Info);
+
+    /// Take out the next process to run,
+    /// either on this cpu or on an idle one stealing it.
+    /// Only the processes for which allowed returns true are considered,
+    /// see Proc::allowed_on.
+    fn pick<F: Fn(usize) -> bool>(&mut self, allowed: F) -> Option<usize>;
+
+    /// Take out the process at slot i if it is queued here,
+    /// e.g., to move it after its affinity changed.
+    /// Return false if it is not.
+    fn remove(&mut self, i: usize, info: &mut Self::Info) -> bool;
+
+    /// Check if any queued process is one for which allowed returns true.
+    fn any<F: Fn(usize) -> bool>(&self, allowed: F) -> bool;
+
+    /// Number of processes queued.
+    fn len(&self) -> usize;
+
+    /// Charge a clock tick to the running process at slot i.
+    /// Return true if it should give up the cpu.
+    fn tick(&mut self, i: usize, info: &mut Self::Info) -> bool;
+
+    /// Called once per clock tick, on cpu 0 only.
+    fn clock() {}
+}
+
+/// A FIFO queue of process table slots.
+pub struct RunQueue {
+    slots: [usize; NPROC],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            slots: [0; NPROC],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn push_back(&mut self, i: usize) {
+        if self.len == NPROC {
+            panic!("run queue: full");
+        }
+        self.slots[(self.head + self.len) % NPROC] = i;
+        self.len += 1;
+    }
+
+    /// Take out the first slot for which f returns true.
+    pub fn take<F: Fn(usize) -> bool>(&mut self, f: F) -> Option<usize> {
+        let pos = (0..self.len).find(|&n| f(self.slots[(self.head + n) % NPROC]))?;
+        let i = self.slots[(self.head + pos) % NPROC];
+        for n in pos..self.len - 1 {
+            self.slots[(self.head + n) % NPROC] = self.slots[(self.head + n + 1) % NPROC];
+        }
+        self.len -= 1;
+        Some(i)
+    }
+
+    /// Check if f returns true for any slot.
+    pub fn any<F: Fn(usize) -> bool>(&self, f: F) -> bool {
+        (0..self.len).any(|n| f(self.slots[(self.head + n) % NPROC]))
+    }
+
+    pub fn pop_front(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None
+        }
+        let i = self.slots[self.head];
+        self.head = (self.head + 1) % NPROC;
+        self.len -= 1;
+        Some(i)
+    }
+}
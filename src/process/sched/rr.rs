@@ -0,0 +1,47 @@
+//! Round-robin scheduling
+
+use super::{RunQueue, Scheduler};
+
+/// Run the processes in the order they become runnable,
+/// each for one clock tick at a time.
+pub struct RoundRobin {
+    queue: RunQueue,
+}
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        Self {
+            queue: RunQueue::new(),
+        }
+    }
+}
+
+impl Scheduler for RoundRobin {
+    type Info = ();
+
+    const INFO: () = ();
+
+    fn enqueue(&mut self, i: usize, _info: &mut ()) {
+        self.queue.push_back(i);
+    }
+
+    fn pick<F: Fn(usize) -> bool>(&mut self, allowed: F) -> Option<usize> {
+        self.queue.take(allowed)
+    }
+
+    fn remove(&mut self, i: usize, _info: &mut ()) -> bool {
+        self.queue.take(|j| j == i).is_some()
+    }
+
+    fn any<F: Fn(usize) -> bool>(&self, allowed: F) -> bool {
+        self.queue.any(allowed)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn tick(&mut self, _i: usize, _info: &mut ()) -> bool {
+        true
+    }
+}
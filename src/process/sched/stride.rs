@@ -0,0 +1,143 @@
+//! Stride scheduling
+
+use crate::consts::NPROC;
+use super::Scheduler;
+
+/// Stride of a process holding one ticket.
+const STRIDE1: usize = 1 << 20;
+
+/// Tickets of a process not calling settickets.
+const DEFAULT_TICKETS: usize = 100;
+
+/// Per-process state.
+#[derive(Clone, Copy)]
+pub struct Pass {
+    tickets: usize,
+    /// STRIDE1 / tickets, added to pass for each tick run
+    stride: usize,
+    pass: usize,
+}
+
+/// Share the cpu in proportion to tickets,
+/// by always running the queued process with the lowest pass.
+pub struct Stride {
+    /// queued slots with their passes
+    queue: [(usize, usize); NPROC],
+    len: usize,
+    /// pass of the process picked last,
+    /// which a process waking up catches up with,
+    /// so that it can not save up its share while sleeping
+    global_pass: usize,
+}
+
+impl Stride {
+    pub const fn new() -> Self {
+        Self {
+            queue: [(0, 0); NPROC],
+            len: 0,
+            global_pass: 0,
+        }
+    }
+}
+
+impl Scheduler for Stride {
+    type Info = Pass;
+
+    const INFO: Pass = Pass {
+        tickets: DEFAULT_TICKETS,
+        stride: STRIDE1 / DEFAULT_TICKETS,
+        pass: 0,
+    };
+
+    /// A child holds as many tickets as its parent.
+    fn inherit(parent: &Pass) -> Pass {
+        Pass {
+            pass: 0,
+            ..*parent
+        }
+    }
+
+    fn set_tickets(info: &mut Pass, tickets: usize) -> Result<(), &'static str> {
+        if tickets == 0 || tickets > STRIDE1 {
+            return Err("settickets: tickets out of range")
+        }
+        info.tickets = tickets;
+        info.stride = STRIDE1 / tickets;
+        Ok(())
+    }
+
+    fn enqueue(&mut self, i: usize, info: &mut Pass) {
+        if self.len == NPROC {
+            panic!("stride: queue full");
+        }
+        if info.pass < self.global_pass {
+            info.pass = self.global_pass;
+        }
+        self.queue[self.len] = (i, info.pass);
+        self.len += 1;
+    }
+
+    fn pick<F: Fn(usize) -> bool>(&mut self, allowed: F) -> Option<usize> {
+        let pos = self.queue[..self.len].iter()
+            .enumerate()
+            .filter(|(_, &(i, _))| allowed(i))
+            .min_by_key(|(_, &(_, pass))| pass)?
+            .0;
+        let (i, pass) = self.queue[pos];
+        self.len -= 1;
+        self.queue[pos] = self.queue[self.len];
+        self.global_pass = pass;
+        Some(i)
+    }
+
+    fn remove(&mut self, i: usize, _info: &mut Pass) -> bool {
+        match self.queue[..self.len].iter().position(|&(j, _)| j == i) {
+            Some(pos) => {
+                self.len -= 1;
+                self.queue[pos] = self.queue[self.len];
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn any<F: Fn(usize) -> bool>(&self, allowed: F) -> bool {
+        self.queue[..self.len].iter().any(|&(i, _)| allowed(i))
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn tick(&mut self, _i: usize, info: &mut Pass) -> bool {
+        info.pass += info.stride;
+        true
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Two processes holding 3:1 tickets get the cpu 3:1.
+    pub fn share() {
+        let mut stride = Stride::new();
+        let mut infos = [Stride::INFO; 2];
+        Stride::set_tickets(&mut infos[0], 300).unwrap();
+        Stride::set_tickets(&mut infos[1], 100).unwrap();
+        for (i, info) in infos.iter_mut().enumerate() {
+            stride.enqueue(i, info);
+        }
+
+        let mut runs = [0usize; 2];
+        for _ in 0..400 {
+            let i = stride.pick(|_| true).unwrap();
+            runs[i] += 1;
+            assert!(stride.tick(i, &mut infos[i]));
+            stride.enqueue(i, &mut infos[i]);
+        }
+        assert_eq!(stride.len(), 2);
+        assert_eq!(runs, [300, 100]);
+        println!("stride share: {}:{}", runs[0], runs[1]);
+    }
+}
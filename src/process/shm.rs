@@ -0,0 +1,163 @@
+//! System V-style shared memory
+//!
+//! A segment is a set of physical pages, created by shmget and
+//! mapped into the address spaces attaching it by shmat as a shared area,
+//! see Vma::attach. The segment and each page mapping it hold a reference
+//! to every page, so the pages live on until the segment is removed by
+//! shmctl(IPC_RMID) and the last mapping is gone, by shmdt, exit or exec.
+
+use array_macro::array;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::consts::{IPC_PRIVATE, NSHM, PGSIZE, SHMMAX};
+use crate::mm::{RawPage, pg_round_up};
+use crate::spinlock::SpinLock;
+use super::proc::Proc;
+
+/// The physical pages of a shared memory segment.
+pub struct Segment {
+    pages: Vec<usize>,
+}
+
+impl Segment {
+    /// Allocate a zeroed segment of size bytes, which must be page-aligned.
+    fn new(size: usize) -> Result<Self, &'static str> {
+        let mut seg = Self {
+            pages: Vec::with_capacity(size / PGSIZE),
+        };
+        for _ in 0..size / PGSIZE {
+            // the pages allocated so far are freed by drop
+            let mem = unsafe { RawPage::try_new_zeroed() }
+                .map_err(|_| "shmget: out of memory")?;
+            seg.pages.push(mem);
+        }
+        Ok(seg)
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.pages.len() * PGSIZE
+    }
+
+    /// Physical address of the n-th page.
+    #[inline]
+    pub fn page(&self, n: usize) -> usize {
+        self.pages[n]
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        for &pa in self.pages.iter() {
+            unsafe { RawPage::put(pa); }
+        }
+    }
+}
+
+struct ShmSlot {
+    /// bumped each time the slot is reused, so a stale id is rejected
+    seq: usize,
+    /// key and segment, None if the slot is free
+    seg: Option<(usize, Arc<Segment>)>,
+}
+
+static SHM: SpinLock<[ShmSlot; NSHM]> =
+    SpinLock::new(array![_ => ShmSlot { seq: 0, seg: None }; NSHM], "shm");
+
+#[inline]
+fn make_id(slot: usize, seq: usize) -> usize {
+    seq * NSHM + slot
+}
+
+/// Look up the segment of key at least size bytes large, return its id.
+fn find(slots: &[ShmSlot; NSHM], key: usize, size: usize) -> Option<Result<usize, &'static str>> {
+    slots.iter().enumerate().find_map(|(i, slot)| match &slot.seg {
+        Some((k, seg)) if *k == key => Some(if seg.size() >= size {
+            Ok(make_id(i, slot.seq))
+        } else {
+            Err("shmget: segment too small")
+        }),
+        _ => None,
+    })
+}
+
+/// Return the id of the segment of key, creating it of size bytes if none,
+/// or always creating a new one if key is IPC_PRIVATE.
+pub fn shm_get(key: usize, size: usize) -> Result<usize, &'static str> {
+    if size == 0 || size > SHMMAX {
+        return Err("shmget: invalid size")
+    }
+    let size = pg_round_up(size);
+    if key != IPC_PRIVATE {
+        if let Some(res) = find(&SHM.lock(), key, size) {
+            return res
+        }
+    }
+
+    // allocate with the table unlocked
+    let seg = Arc::new(Segment::new(size)?);
+    let mut slots = SHM.lock();
+    if key != IPC_PRIVATE {
+        // created by another process meanwhile
+        if let Some(res) = find(&slots, key, size) {
+            drop(slots);
+            drop(seg);
+            return res
+        }
+    }
+    let res = match slots.iter().position(|slot| slot.seg.is_none()) {
+        Some(i) => {
+            slots[i].seg = Some((key, seg));
+            Ok(make_id(i, slots[i].seq))
+        }
+        None => Err("shmget: too many segments"),
+    };
+    drop(slots);
+    res
+}
+
+/// Return the segment of id.
+fn shm_lookup(id: usize) -> Result<Arc<Segment>, &'static str> {
+    let slots = SHM.lock();
+    let slot = &slots[id % NSHM];
+    let res = match &slot.seg {
+        Some((_, seg)) if slot.seq == id / NSHM => Ok(Arc::clone(seg)),
+        _ => Err("shm: invalid id"),
+    };
+    drop(slots);
+    res
+}
+
+/// Remove the segment of id, so that no one can attach it any more.
+/// Its pages are freed once the attached ones detach it.
+pub fn shm_remove(id: usize) -> Result<(), &'static str> {
+    let mut slots = SHM.lock();
+    let slot = &mut slots[id % NSHM];
+    if slot.seg.is_none() || slot.seq != id / NSHM {
+        drop(slots);
+        return Err("shmctl: invalid id")
+    }
+    let seg = slot.seg.take();
+    slot.seq += 1;
+    drop(slots);
+    drop(seg);
+    Ok(())
+}
+
+impl Proc {
+    /// Attach the segment of id, read-write, below the mapped areas.
+    /// Return the start address.
+    pub fn shm_attach(&mut self, id: usize) -> Result<usize, &'static str> {
+        let seg = shm_lookup(id)?;
+        self.data.get_mut().space().lock().shmat(seg)
+    }
+
+    /// Detach the segment attached at addr.
+    pub fn shm_detach(&mut self, addr: usize) -> Result<(), &'static str> {
+        let vma = self.data.get_mut().space().lock().shmdt(addr)?;
+        drop(vma);
+        Ok(())
+    }
+}
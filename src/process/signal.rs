@@ -0,0 +1,339 @@
+//! POSIX-style signals
+//!
+//! A signal is posted to a process by kill(), a child's exit or a bad fault,
+//! and delivered on its way back to user space, see user_trap_ret.
+//! A caught signal runs the user handler on a signal frame pushed on the
+//! user stack, and the handler returns to the restorer given by sigaction,
+//! which calls rt_sigreturn to restore the interrupted trapframe.
+//! A process sleeping in a system call is woken up by a caught signal,
+//! and the sleep loop returns an error, see Proc::interrupted,
+//! so that the handler runs on the way back to user space.
+
+use array_macro::array;
+
+use core::mem;
+
+use crate::consts::signal::*;
+use super::cpu::CPU_MANAGER;
+use super::proc::{Proc, ProcExcl, ProcState};
+use super::TrapFrame;
+
+/// The action taken on a signal, shared with user space.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SigAction {
+    pub handler: usize,
+    /// where the handler returns to, which should call rt_sigreturn
+    pub restorer: usize,
+    /// signals blocked while the handler runs
+    pub mask: u32,
+}
+
+impl SigAction {
+    const fn new() -> Self {
+        Self {
+            handler: SIG_DFL,
+            restorer: 0,
+            mask: 0,
+        }
+    }
+}
+
+/// Pushed on the user stack when a caught signal is delivered.
+#[repr(C)]
+struct SigFrame {
+    tf: TrapFrame,
+    blocked: u32,
+    sig: u32,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+#[inline]
+fn sig_bit(sig: usize) -> u32 {
+    1 << sig
+}
+
+/// Signals that cannot be caught, blocked or ignored.
+const UNBLOCKABLE: u32 = (1 << SIGKILL) | (1 << SIGSTOP);
+/// Signals whose default action is to stop.
+const STOP_MASK: u32 = (1 << SIGSTOP) | (1 << SIGTSTP) | (1 << SIGTTIN) | (1 << SIGTTOU);
+
+/// What the sender should do to the receiving process after posting a signal.
+pub enum SigEffect {
+    None,
+    /// the process will terminate, so wake it up if sleeping or stopped
+    Kill,
+    /// a caught signal is deliverable, so wake it up if sleeping to run the handler
+    Interrupt,
+    /// continue the process if stopped
+    Continue,
+}
+
+/// Signal state of a process, guarded by its ProcExcl lock.
+#[derive(Clone)]
+pub struct SigState {
+    pending: u32,
+    blocked: u32,
+    actions: [SigAction; NSIG],
+}
+
+impl SigState {
+    pub const fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: array![_ => SigAction::new(); NSIG],
+        }
+    }
+
+    /// The state inherited by a forked child,
+    /// which has the same actions and mask, but no pending signals.
+    pub fn fork_copy(&self) -> Self {
+        let mut state = self.clone();
+        state.pending = 0;
+        state
+    }
+
+    /// Reset the caught signals to default at exec,
+    /// since the handlers are gone with the old image.
+    pub fn exec_reset(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::new();
+            }
+        }
+    }
+
+    /// Post sig as pending.
+    pub fn post(&mut self, sig: usize) -> SigEffect {
+        let bit = sig_bit(sig);
+        if sig == SIGKILL {
+            self.pending |= bit;
+            return SigEffect::Kill
+        }
+        if sig == SIGCONT {
+            self.pending &= !STOP_MASK;
+        } else if bit & STOP_MASK > 0 {
+            self.pending &= !sig_bit(SIGCONT);
+        }
+
+        let handler = self.actions[sig].handler;
+        let ignored = handler == SIG_IGN ||
+            (handler == SIG_DFL && default_action(sig) == DefaultAction::Ignore);
+        if !ignored || self.blocked & bit > 0 {
+            self.pending |= bit;
+        }
+
+        if sig == SIGCONT {
+            SigEffect::Continue
+        } else if self.blocked & bit > 0 {
+            SigEffect::None
+        } else if handler == SIG_DFL && default_action(sig) == DefaultAction::Terminate {
+            SigEffect::Kill
+        } else if handler != SIG_DFL && handler != SIG_IGN {
+            SigEffect::Interrupt
+        } else {
+            SigEffect::None
+        }
+    }
+
+    /// Check if a pending signal that is not blocked has a handler to run.
+    pub fn interrupting(&self) -> bool {
+        let deliverable = self.pending & !self.blocked;
+        (1..NSIG).any(|sig| deliverable & sig_bit(sig) > 0 &&
+            self.actions[sig].handler != SIG_DFL && self.actions[sig].handler != SIG_IGN)
+    }
+
+    /// Make sure a synchronous signal, e.g., SIGSEGV at a bad fault,
+    /// is not blocked or ignored, otherwise the fault repeats forever.
+    pub fn force(&mut self, sig: usize) -> SigEffect {
+        let bit = sig_bit(sig);
+        if self.blocked & bit > 0 || self.actions[sig].handler == SIG_IGN {
+            self.blocked &= !bit;
+            self.actions[sig] = SigAction::new();
+        }
+        self.post(sig)
+    }
+
+    /// Take out the lowest pending signal that is not blocked.
+    fn take_next(&mut self) -> Option<usize> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None
+        }
+        let sig = deliverable.trailing_zeros() as usize;
+        self.pending &= !sig_bit(sig);
+        Some(sig)
+    }
+
+    pub fn get_action(&self, sig: usize) -> SigAction {
+        self.actions[sig]
+    }
+
+    /// Set the action of sig, which must be catchable.
+    pub fn set_action(&mut self, sig: usize, action: SigAction) {
+        self.actions[sig] = action;
+        if action.handler == SIG_IGN ||
+            (action.handler == SIG_DFL && default_action(sig) == DefaultAction::Ignore)
+        {
+            // a pending signal being ignored is discarded
+            self.pending &= !sig_bit(sig);
+        }
+    }
+
+    pub fn get_blocked(&self) -> u32 {
+        self.blocked
+    }
+
+    pub fn set_blocked(&mut self, blocked: u32) {
+        self.blocked = blocked & !UNBLOCKABLE & !1;
+    }
+}
+
+/// Check if sig is a valid signal number.
+#[inline]
+pub fn sig_valid(sig: usize) -> bool {
+    sig > 0 && sig < NSIG
+}
+
+/// Check if the action of sig can be changed.
+#[inline]
+pub fn sig_catchable(sig: usize) -> bool {
+    sig_valid(sig) && sig_bit(sig) & UNBLOCKABLE == 0
+}
+
+impl ProcExcl {
+    /// Post sig to the process.
+    /// A caught signal is delivered when the process next returns to user space.
+    /// Return true if the process has to act on it at once,
+    /// so the caller should make it runnable, see ProcManager::set_runnable.
+    pub fn post_signal(&mut self, sig: usize) -> bool {
+        let effect = self.signals.post(sig);
+        self.apply_effect(effect)
+    }
+
+    fn apply_effect(&mut self, effect: SigEffect) -> bool {
+        match effect {
+            SigEffect::None => false,
+            SigEffect::Kill => {
+                self.killed = true;
+                self.state == ProcState::SLEEPING || self.state == ProcState::STOPPED
+            }
+            SigEffect::Interrupt => self.state == ProcState::SLEEPING,
+            SigEffect::Continue => self.state == ProcState::STOPPED,
+        }
+    }
+}
+
+impl Proc {
+    /// Post a signal caused by the running process itself,
+    /// e.g., SIGSEGV at a bad fault, which can not be blocked or ignored.
+    pub fn force_signal(&self, sig: usize) {
+        let mut guard = self.excl.lock();
+        let effect = guard.signals.force(sig);
+        guard.apply_effect(effect);
+        drop(guard);
+    }
+
+    /// Deliver the pending signals that are not blocked.
+    /// Called on the way back to user space.
+    /// Only one caught signal is delivered at a time,
+    /// the others are delivered after its handler returns.
+    pub fn handle_signals(&mut self) {
+        loop {
+            let mut guard = self.excl.lock();
+            let sig = match guard.signals.take_next() {
+                Some(sig) => sig,
+                None => break,
+            };
+            let action = guard.signals.get_action(sig);
+            match action.handler {
+                SIG_IGN => {},
+                SIG_DFL => match default_action(sig) {
+                    DefaultAction::Ignore | DefaultAction::Continue => {},
+                    DefaultAction::Terminate => {
+                        drop(guard);
+                        self.exit(-1);
+                    }
+                    DefaultAction::Stop => {
+                        // until SIGCONT or SIGKILL sets it runnable
+                        guard.state = ProcState::STOPPED;
+                        unsafe {
+                            let c = CPU_MANAGER.my_cpu_mut();
+                            guard = c.sched(guard, self.data.get_mut().get_context());
+                        }
+                    }
+                },
+                handler => {
+                    let old_blocked = guard.signals.get_blocked();
+                    guard.signals.set_blocked(old_blocked | action.mask | sig_bit(sig));
+                    drop(guard);
+                    if self.push_sig_frame(sig, old_blocked, handler, action.restorer).is_err() {
+                        // cannot deliver, e.g., the user stack overflows
+                        self.exit(-1);
+                    }
+                    return
+                }
+            }
+            drop(guard);
+        }
+    }
+
+    /// Save the trapframe on the user stack,
+    /// and divert the return to user space to the handler.
+    fn push_sig_frame(&mut self, sig: usize, blocked: u32, handler: usize, restorer: usize)
+        -> Result<(), &'static str>
+    {
+        let pd = self.data.get_mut();
+        let tf = pd.tf_mut();
+        let mut frame = SigFrame {
+            tf: *tf,
+            blocked,
+            sig: sig as u32,
+        };
+        frame.tf.clear_kernel();
+        let sp = tf.sp.checked_sub(mem::size_of::<SigFrame>())
+            .ok_or("signal: user stack overflow")? & !0xf;
+        pd.copy_out(&frame as *const SigFrame as *const u8, sp, mem::size_of::<SigFrame>())?;
+
+        let tf = pd.tf_mut();
+        tf.sp = sp;
+        tf.epc = handler;
+        tf.ra = restorer;
+        tf.a0 = sig;
+        Ok(())
+    }
+
+    /// Return from a signal handler,
+    /// by restoring the trapframe and blocked mask in the signal frame,
+    /// which is at the top of the user stack.
+    /// Return the interrupted a0, so the syscall return does not clobber it.
+    pub fn sig_return(&mut self) -> Result<usize, &'static str> {
+        let pd = self.data.get_mut();
+        let sp = pd.tf_mut().sp;
+        let mut frame: SigFrame = unsafe { mem::MaybeUninit::zeroed().assume_init() };
+        pd.copy_in(sp, &mut frame as *mut SigFrame as *mut u8, mem::size_of::<SigFrame>())?;
+
+        let tf = pd.tf_mut();
+        frame.tf.keep_kernel(tf);
+        *tf = frame.tf;
+        self.excl.lock().signals.set_blocked(frame.blocked);
+        Ok(frame.tf.a0)
+    }
+}
@@ -0,0 +1,324 @@
+//! User address space, shared by the threads of a process
+
+use array_macro::array;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use crate::consts::{NVMA, PGSIZE, TRAMPOLINE, USERTOP};
+use crate::fs::File;
+use crate::mm::{Addr, PageTable, PhysAddr, PteFlag, RawPage, VirtAddr, pg_round_up};
+use super::shm::Segment;
+use super::vma::{Vma, WriteBack};
+
+/// What is left to do to make a user page present, see UserSpace::lazy.
+pub enum Fill {
+    /// the page is present
+    Done,
+    /// the page is to be read from the file of the area,
+    /// with the address space unlocked, see Vma::fill
+    File(Vma),
+    /// the page is not part of the user memory
+    Outside,
+}
+
+/// User memory and mapped areas of a process.
+/// The threads created by clone share it, each with its trapframe mapped in.
+/// It is locked by a SleepLock, since file-backed page faults read the file.
+/// The lock must not be held while reading or writing a file,
+/// because a file read or write copies to or from user memory
+/// with the inode locked, which locks the address space in turn.
+pub struct UserSpace {
+    pagetable: Box<PageTable>,
+    /// user memory is [0, sz)
+    sz: usize,
+    vmas: [Option<Vma>; NVMA],
+    /// number of threads using it
+    threads: usize,
+}
+
+impl UserSpace {
+    /// Create a user page table with no user memory,
+    /// but with trampoline code mapped.
+    pub fn make_pagetable() -> Result<Box<PageTable>, &'static str> {
+        extern "C" {
+            fn trampoline();
+        }
+
+        let mut pagetable = PageTable::uvm_create();
+        if let Err(err) = pagetable.map_pages(
+            VirtAddr::from(TRAMPOLINE),
+            PGSIZE,
+            PhysAddr::try_from(trampoline as usize).unwrap(),
+            PteFlag::R | PteFlag::X,
+        ) {
+            pagetable.uvm_free(0);
+            return Err(err)
+        }
+        Ok(pagetable)
+    }
+
+    /// Free a page table made by make_pagetable,
+    /// and the user memory [0, sz) it refers to.
+    pub fn free_pagetable(mut pagetable: Box<PageTable>, sz: usize) {
+        pagetable.uvm_unmap(TRAMPOLINE.into(), 1, false);
+        pagetable.uvm_free(sz);
+    }
+
+    /// Create an address space with user memory [0, sz) mapped in pagetable,
+    /// which is made by make_pagetable. No thread uses it yet.
+    pub fn new(pagetable: Box<PageTable>, sz: usize) -> Self {
+        Self {
+            pagetable,
+            sz,
+            vmas: array![_ => None; NVMA],
+            threads: 0,
+        }
+    }
+
+    /// Create an empty address space.
+    pub fn empty() -> Result<Self, &'static str> {
+        Ok(Self::new(Self::make_pagetable()?, 0))
+    }
+
+    #[inline]
+    pub fn pagetable_mut(&mut self) -> &mut PageTable {
+        &mut self.pagetable
+    }
+
+    #[inline]
+    pub fn get_sz(&self) -> usize {
+        self.sz
+    }
+
+    #[inline]
+    pub fn set_sz(&mut self, sz: usize) {
+        self.sz = sz;
+    }
+
+    /// Map the trapframe at physical address tf of a thread
+    /// starting to use this address space at tf_va.
+    pub fn enter(&mut self, tf_va: usize, tf: usize) -> Result<(), &'static str> {
+        self.pagetable.map_pages(
+            VirtAddr::try_from(tf_va).unwrap(),
+            PGSIZE,
+            PhysAddr::try_from(tf).unwrap(),
+            PteFlag::R | PteFlag::W,
+        )?;
+        self.threads += 1;
+        Ok(())
+    }
+
+    /// Unmap the trapframe of a thread no longer using this address space.
+    /// If it is the last thread, also unmap all the mapped areas,
+    /// and return their dirty pages to write back.
+    /// The user memory itself is freed when the address space is dropped.
+    pub fn leave(&mut self, tf_va: usize) -> Vec<WriteBack> {
+        self.pagetable.uvm_unmap(tf_va, 1, false);
+        self.threads -= 1;
+        let mut write_backs = Vec::new();
+        if self.threads == 0 {
+            for vma in self.vmas.iter_mut() {
+                if let Some(mut vma) = vma.take() {
+                    let (start, end) = (vma.start(), vma.end());
+                    if let Some(wb) = vma.unmap(&mut self.pagetable, start, end).expect("leave") {
+                        write_backs.push(wb);
+                    }
+                }
+            }
+        }
+        write_backs
+    }
+
+    /// Give a child at fork a copy of the user memory and the mapped areas.
+    /// Free any mappings in the child on failure.
+    pub fn copy(&mut self, child: &mut Self) -> Result<(), &'static str> {
+        self.pagetable.uvm_copy(&mut child.pagetable, self.sz)?;
+        child.sz = self.sz;
+        for (vma, cvma) in self.vmas.iter().zip(child.vmas.iter_mut()) {
+            if let Some(vma) = vma {
+                vma.copy(&mut self.pagetable, &mut child.pagetable)?;
+                *cvma = Some(vma.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Grow or shrink user memory by n bytes.
+    /// Growing only bumps sz, the pages are allocated at page fault.
+    /// Return the old size, which is the start of the new memory if growing.
+    pub fn grow(&mut self, n: i32) -> Result<usize, &'static str> {
+        let old_sz = self.sz;
+        if n > 0 {
+            let new_sz = old_sz.checked_add(n as usize).ok_or("grow: size overflow")?;
+            if new_sz > self.vma_bottom() {
+                return Err("grow: size too large")
+            }
+            self.sz = new_sz;
+        } else if n < 0 {
+            let new_sz = old_sz.checked_sub((-(n as isize)) as usize)
+                .ok_or("grow: shrink below zero")?;
+            self.sz = self.pagetable.uvm_dealloc(old_sz, new_sz);
+        }
+        Ok(old_sz)
+    }
+
+    /// Handle a user page fault at virtual address va.
+    pub fn fault(&mut self, va: usize, store: bool) -> Result<Fill, &'static str> {
+        let pagetable = &mut self.pagetable;
+        match self.vmas.iter().flatten().find(|vma| vma.contains(va)) {
+            Some(vma) => match vma.fault(pagetable, va, store)? {
+                true => Ok(Fill::File(vma.clone())),
+                false => Ok(Fill::Done),
+            },
+            None => pagetable.uvm_fault(va, self.sz, store).map(|_| Fill::Done),
+        }
+    }
+
+    /// Allocate the user page at va if it is not allocated yet,
+    /// so the kernel can copy in or out of it.
+    pub fn lazy(&mut self, va: usize) -> Result<Fill, &'static str> {
+        if va < self.sz {
+            self.pagetable.uvm_lazy(va)?;
+            return Ok(Fill::Done)
+        }
+        let pagetable = &mut self.pagetable;
+        match self.vmas.iter().flatten().find(|vma| vma.contains(va)) {
+            Some(_) if pagetable.walk_flags(va).is_some() => Ok(Fill::Done),
+            Some(vma) => match vma.fault(pagetable, va, false)? {
+                true => Ok(Fill::File(vma.clone())),
+                false => Ok(Fill::Done),
+            },
+            None => Ok(Fill::Outside),
+        }
+    }
+
+    /// Return the physical address of the present user byte at va,
+    /// and whether it is in a shared area, see Vma::is_shared.
+    pub fn translate(&self, va: usize) -> Result<(usize, bool), &'static str> {
+        let pa = self.pagetable.walk_addr(VirtAddr::try_from(va)?)?;
+        let shared = self.vmas.iter().flatten().any(|vma| vma.contains(va) && vma.is_shared());
+        Ok((pa.as_usize() + va % PGSIZE, shared))
+    }
+
+    /// Map the page mem filled in by Vma::fill at va,
+    /// unless another thread did it meanwhile.
+    /// mem is freed if it is not used.
+    pub fn install(&mut self, va: usize, mem: usize) -> Result<(), &'static str> {
+        let pagetable = &mut self.pagetable;
+        match self.vmas.iter().flatten().find(|vma| vma.contains(va)) {
+            Some(_) if pagetable.walk_flags(va).is_some() => {
+                unsafe { RawPage::from_raw_and_drop(mem); }
+                Ok(())
+            }
+            Some(vma) => vma.map(pagetable, va, mem),
+            None => {
+                unsafe { RawPage::from_raw_and_drop(mem); }
+                Err("mmap: area unmapped")
+            }
+        }
+    }
+
+    /// The lowest address of the mapped areas,
+    /// under which the user memory can grow.
+    fn vma_bottom(&self) -> usize {
+        self.vmas.iter().flatten()
+            .map(|vma| vma.start())
+            .min()
+            .unwrap_or(USERTOP.into())
+    }
+
+    /// Map len bytes of the file at offset, or anonymous memory if file is None,
+    /// right below the existing mapped areas.
+    /// Return the start address of the new area.
+    pub fn mmap(&mut self, len: usize, prot: i32, shared: bool,
+        file: Option<Arc<File>>, offset: usize) -> Result<usize, &'static str>
+    {
+        let slot = self.vmas.iter().position(|vma| vma.is_none())
+            .ok_or("mmap: too many mappings")?;
+        let len = len.checked_add(PGSIZE - 1).ok_or("mmap: len too large")? & !(PGSIZE - 1);
+        let start = self.area_below(len).ok_or("mmap: no space")?;
+        self.vmas[slot] = Some(Vma::new(start, len, prot, shared, file, offset)?);
+        Ok(start)
+    }
+
+    /// Find room for an area of len bytes, which is page-aligned,
+    /// right below the existing mapped areas.
+    /// Return its start address.
+    fn area_below(&self, len: usize) -> Option<usize> {
+        let start = self.vma_bottom().checked_sub(len)?;
+        if start < pg_round_up(self.sz) {
+            return None
+        }
+        Some(start)
+    }
+
+    /// Map the shared memory segment right below the existing mapped areas.
+    /// Return the start address of the new area.
+    pub fn shmat(&mut self, segment: Arc<Segment>) -> Result<usize, &'static str> {
+        let slot = self.vmas.iter().position(|vma| vma.is_none())
+            .ok_or("shmat: too many mappings")?;
+        let start = self.area_below(segment.size()).ok_or("shmat: no space")?;
+        let vma = Vma::attach(start, segment);
+        vma.map_segment(&mut self.pagetable)?;
+        self.vmas[slot] = Some(vma);
+        Ok(start)
+    }
+
+    /// Unmap the shared memory segment attached at addr.
+    /// Return the area, so that the caller can drop its segment reference
+    /// with the address space unlocked.
+    pub fn shmdt(&mut self, addr: usize) -> Result<Vma, &'static str> {
+        let slot = self.vmas.iter()
+            .position(|vma| vma.as_ref().map_or(false, |vma| vma.is_attached_at(addr)))
+            .ok_or("shmdt: no segment attached at addr")?;
+        let mut vma = self.vmas[slot].take().unwrap();
+        let (start, end) = (vma.start(), vma.end());
+        vma.unmap(&mut self.pagetable, start, end)?;
+        Ok(vma)
+    }
+
+    /// Unmap [addr, addr+len) from a mapped area.
+    /// Return the dirty pages to write back if it is a shared file mapping,
+    /// and the area if it is unmapped entirely, so that the caller can
+    /// drop its file reference with the address space unlocked.
+    pub fn munmap(&mut self, addr: usize, len: usize)
+        -> Result<(Option<WriteBack>, Option<Vma>), &'static str>
+    {
+        if addr % PGSIZE != 0 {
+            return Err("munmap: addr not aligned")
+        }
+        let end = len.checked_add(PGSIZE - 1)
+            .and_then(|len| addr.checked_add(len & !(PGSIZE - 1)))
+            .ok_or("munmap: len too large")?;
+        let slot = self.vmas.iter()
+            .position(|vma| vma.as_ref().map_or(false, |vma| vma.contains(addr)))
+            .ok_or("munmap: addr not mapped")?;
+
+        let vma = self.vmas[slot].as_mut().unwrap();
+        let write_back = vma.unmap(&mut self.pagetable, addr, end)?;
+        let vma = if vma.is_empty() {
+            self.vmas[slot].take()
+        } else {
+            None
+        };
+        Ok((write_back, vma))
+    }
+}
+
+impl Drop for UserSpace {
+    /// Free the user memory, the pages of the areas left mapped,
+    /// e.g., by a fork failing halfway, and the page table.
+    /// The trapframes must have been unmapped.
+    fn drop(&mut self) {
+        for vma in self.vmas.iter_mut() {
+            if let Some(vma) = vma.take() {
+                self.pagetable.uvm_unmap(vma.start(), (vma.end() - vma.start()) / PGSIZE, true);
+            }
+        }
+        self.pagetable.uvm_unmap(TRAMPOLINE.into(), 1, false);
+        self.pagetable.uvm_free(self.sz);
+    }
+}
@@ -1,48 +1,755 @@
+use array_macro::array;
+
+use alloc::boxed::Box;
 use core::mem;
 
-use crate::consts::{MAXPATH};
+use crate::consts::{CLOCK_MONOTONIC, FUTEX_WAIT, FUTEX_WAKE, IPC_RMID, MAXARG, MAXPATH, PGSIZE, MAP_ANONYMOUS, MAP_PRIVATE, MAP_SHARED, PROT_WRITE};
+use crate::fs::{File, InodeType, LOG, Socket, create, link, unlink, namei};
+use crate::trap::{clock_read, clock_sleep};
+use crate::timer::{self, TimeSpec};
+use crate::consts::fs::AF_UNIX;
+use crate::consts::signal::{SIG_BLOCK, SIG_DFL, SIG_SETMASK, SIG_UNBLOCK};
+use super::elf;
+use super::signal::{SigAction, sig_catchable};
+use super::shm::{shm_get, shm_remove};
+use super::PROC_MANAGER;
 use super::proc::Proc;
 
+/// The result of a system call.
+/// Err is returned to the user as -1.
+pub type SysResult = Result<usize, &'static str>;
+
 pub trait Syscall {
-    fn sys_exec(&mut self) -> usize;
+    fn sys_fork(&mut self) -> SysResult;
+    fn sys_exit(&mut self) -> SysResult;
+    fn sys_wait(&mut self) -> SysResult;
+    fn sys_pipe(&mut self) -> SysResult;
+    fn sys_read(&mut self) -> SysResult;
+    fn sys_kill(&mut self) -> SysResult;
+    fn sys_exec(&mut self) -> SysResult;
+    fn sys_fstat(&mut self) -> SysResult;
+    fn sys_chdir(&mut self) -> SysResult;
+    fn sys_dup(&mut self) -> SysResult;
+    fn sys_getpid(&mut self) -> SysResult;
+    fn sys_sbrk(&mut self) -> SysResult;
+    fn sys_sleep(&mut self) -> SysResult;
+    fn sys_uptime(&mut self) -> SysResult;
+    fn sys_open(&mut self) -> SysResult;
+    fn sys_write(&mut self) -> SysResult;
+    fn sys_mknod(&mut self) -> SysResult;
+    fn sys_unlink(&mut self) -> SysResult;
+    fn sys_link(&mut self) -> SysResult;
+    fn sys_mkdir(&mut self) -> SysResult;
+    fn sys_close(&mut self) -> SysResult;
+    fn sys_mmap(&mut self) -> SysResult;
+    fn sys_munmap(&mut self) -> SysResult;
+    fn sys_sigalarm(&mut self) -> SysResult;
+    fn sys_sigreturn(&mut self) -> SysResult;
+    fn sys_sigaction(&mut self) -> SysResult;
+    fn sys_sigprocmask(&mut self) -> SysResult;
+    fn sys_rt_sigreturn(&mut self) -> SysResult;
+    fn sys_clone(&mut self) -> SysResult;
+    fn sys_join(&mut self) -> SysResult;
+    fn sys_settickets(&mut self) -> SysResult;
+    fn sys_getticks(&mut self) -> SysResult;
+    fn sys_sched_setaffinity(&mut self) -> SysResult;
+    fn sys_sched_getaffinity(&mut self) -> SysResult;
+    fn sys_nanosleep(&mut self) -> SysResult;
+    fn sys_clock_gettime(&mut self) -> SysResult;
+    fn sys_futex(&mut self) -> SysResult;
+    fn sys_shmget(&mut self) -> SysResult;
+    fn sys_shmat(&mut self) -> SysResult;
+    fn sys_shmdt(&mut self) -> SysResult;
+    fn sys_shmctl(&mut self) -> SysResult;
+    fn sys_endpoint_create(&mut self) -> SysResult;
+    fn sys_send(&mut self) -> SysResult;
+    fn sys_recv(&mut self) -> SysResult;
+    fn sys_call(&mut self) -> SysResult;
+    fn sys_reply(&mut self) -> SysResult;
+    fn sys_socket(&mut self) -> SysResult;
+    fn sys_bind(&mut self) -> SysResult;
+    fn sys_listen(&mut self) -> SysResult;
+    fn sys_accept(&mut self) -> SysResult;
+    fn sys_connect(&mut self) -> SysResult;
+    fn sys_sendto(&mut self) -> SysResult;
+    fn sys_recvfrom(&mut self) -> SysResult;
+}
+
+/// Number of slots in the system call table.
+const NSYSCALL: usize = 54;
+
+/// System call table, indexed by the system call number in a7.
+/// Keep the numbers in sync with asm/syscall.h.
+static SYSCALLS: [Option<fn(&mut Proc) -> SysResult>; NSYSCALL] = [
+    None,
+    Some(Proc::sys_fork),   // 1
+    Some(Proc::sys_exit),   // 2
+    Some(Proc::sys_wait),   // 3
+    Some(Proc::sys_pipe),   // 4
+    Some(Proc::sys_read),   // 5
+    Some(Proc::sys_kill),   // 6
+    Some(Proc::sys_exec),   // 7
+    Some(Proc::sys_fstat),  // 8
+    Some(Proc::sys_chdir),  // 9
+    Some(Proc::sys_dup),    // 10
+    Some(Proc::sys_getpid), // 11
+    Some(Proc::sys_sbrk),   // 12
+    Some(Proc::sys_sleep),  // 13
+    Some(Proc::sys_uptime), // 14
+    Some(Proc::sys_open),   // 15
+    Some(Proc::sys_write),  // 16
+    Some(Proc::sys_mknod),  // 17
+    Some(Proc::sys_unlink), // 18
+    Some(Proc::sys_link),   // 19
+    Some(Proc::sys_mkdir),  // 20
+    Some(Proc::sys_close),  // 21
+    Some(Proc::sys_mmap),   // 22
+    Some(Proc::sys_munmap), // 23
+    Some(Proc::sys_sigalarm),  // 24
+    Some(Proc::sys_sigreturn), // 25
+    Some(Proc::sys_sigaction),    // 26
+    Some(Proc::sys_sigprocmask),  // 27
+    Some(Proc::sys_rt_sigreturn), // 28
+    Some(Proc::sys_clone),  // 29
+    Some(Proc::sys_join),   // 30
+    Some(Proc::sys_settickets), // 31
+    Some(Proc::sys_getticks),   // 32
+    Some(Proc::sys_sched_setaffinity), // 33
+    Some(Proc::sys_sched_getaffinity), // 34
+    Some(Proc::sys_nanosleep),     // 35
+    Some(Proc::sys_clock_gettime), // 36
+    Some(Proc::sys_futex),  // 37
+    Some(Proc::sys_shmget), // 38
+    Some(Proc::sys_shmat),  // 39
+    Some(Proc::sys_shmdt),  // 40
+    Some(Proc::sys_shmctl), // 41
+    Some(Proc::sys_endpoint_create), // 42
+    Some(Proc::sys_send),   // 43
+    Some(Proc::sys_recv),   // 44
+    Some(Proc::sys_call),   // 45
+    Some(Proc::sys_reply),  // 46
+    Some(Proc::sys_socket), // 47
+    Some(Proc::sys_bind),   // 48
+    Some(Proc::sys_listen), // 49
+    Some(Proc::sys_accept), // 50
+    Some(Proc::sys_connect), // 51
+    Some(Proc::sys_sendto), // 52
+    Some(Proc::sys_recvfrom), // 53
+];
+
+/// Look up the system call numbered num and call it.
+/// Unknown numbers are reported and returned as an error,
+/// instead of panicking the kernel.
+pub fn dispatch(p: &mut Proc, num: usize) -> SysResult {
+    match SYSCALLS.get(num) {
+        Some(Some(handler)) => handler(p),
+        _ => {
+            let pid = p.excl.lock().pid;
+            println!("pid {}: unknown syscall num: {}", pid, num);
+            Err("unknown syscall")
+        }
+    }
 }
 
 impl Syscall for Proc {
-    fn sys_exec(&mut self) -> usize {
-        // TODO - UB here
-        let mut path: [u8; MAXPATH] = unsafe {
-            mem::MaybeUninit::uninit().assume_init()
+    fn sys_fork(&mut self) -> SysResult {
+        self.fork()
+    }
+
+    fn sys_exit(&mut self) -> SysResult {
+        let status = self.arg_i32(0);
+        self.exit(status)
+    }
+
+    fn sys_wait(&mut self) -> SysResult {
+        let addr = self.arg_raw(0);
+        self.wait(addr)
+    }
+
+    /// Create a pipe, and write its read and write file descriptors
+    /// to the two ints at fdarray.
+    fn sys_pipe(&mut self) -> SysResult {
+        let fdarray = self.arg_raw(0);
+        let (rf, wf) = File::pipe()?;
+        let pd = self.data.get_mut();
+        let fd0 = pd.alloc_fd(rf).map_err(|_| "pipe: no free file descriptor")?;
+        let fd1 = match pd.alloc_fd(wf) {
+            Ok(fd) => fd,
+            Err(_) => {
+                pd.take_file(fd0);
+                return Err("pipe: no free file descriptor")
+            }
+        };
+
+        let fds = [fd0 as i32, fd1 as i32];
+        if pd.copy_out(fds.as_ptr() as *const u8, fdarray, mem::size_of::<[i32; 2]>()).is_err() {
+            pd.take_file(fd0);
+            pd.take_file(fd1);
+            return Err("pipe: bad fdarray")
+        }
+        Ok(0)
+    }
+
+    fn sys_read(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let addr = self.arg_raw(1);
+        let count = self.arg_i32(2);
+        if count < 0 {
+            return Err("read: negative count")
+        }
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        file.read(addr, count as u32).map(|n| n as usize)
+    }
+
+    fn sys_kill(&mut self) -> SysResult {
+        let pid = self.arg_i32(0);
+        let sig = self.arg_i32(1);
+        if pid < 0 {
+            return Err("kill: negative pid")
+        }
+        if sig < 0 {
+            return Err("kill: invalid signal")
+        }
+        unsafe { PROC_MANAGER.kill(pid as usize, sig as usize).map(|_| 0) }
+    }
+
+    fn sys_exec(&mut self) -> SysResult {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        self.arg_str(0, &mut path)?;
+
+        // copy the argument strings into kernel,
+        // the user's argv array is terminated by a null pointer
+        let mut argv: [Option<Box<[u8; PGSIZE]>>; MAXARG] = array![_ => None; MAXARG];
+        let uargv = self.arg_raw(1);
+        let mut i = 0;
+        loop {
+            let uarg = self.fetch_addr(uargv + i * mem::size_of::<usize>())?;
+            if uarg == 0 {
+                break
+            }
+            if i >= MAXARG {
+                return Err("exec: too many arguments")
+            }
+            let mut buf = unsafe { Box::<[u8; PGSIZE]>::new_zeroed().assume_init() };
+            self.fetch_str(uarg, buf.as_mut())?;
+            argv[i] = Some(buf);
+            i += 1;
+        }
+
+        elf::load(self, &path, &argv)
+    }
+
+    fn sys_fstat(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let addr = self.arg_raw(1);
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        file.stat(addr).map(|_| 0)
+    }
+
+    fn sys_chdir(&mut self) -> SysResult {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        self.arg_str(0, &mut path)?;
+
+        LOG.begin_op();
+        let inode = match namei(&path) {
+            Some(inode) => inode,
+            None => {
+                LOG.end_op();
+                return Err("chdir: path not found")
+            }
+        };
+        let idata = inode.lock();
+        if idata.get_itype() != InodeType::Directory {
+            drop(idata);
+            drop(inode);
+            LOG.end_op();
+            return Err("chdir: not a directory")
+        }
+        drop(idata);
+        let old = self.data.get_mut().set_cwd(inode);
+        drop(old);
+        LOG.end_op();
+
+        Ok(0)
+    }
+
+    fn sys_dup(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let pd = self.data.get_mut();
+        let file = pd.get_file(fd).unwrap().clone();
+        pd.alloc_fd(file).map_err(|_| "dup: no free file descriptor")
+    }
+
+    fn sys_getpid(&mut self) -> SysResult {
+        Ok(self.excl.lock().pid)
+    }
+
+    fn sys_sbrk(&mut self) -> SysResult {
+        let n = self.arg_i32(0);
+        self.data.get_mut().grow(n)
+    }
+
+    fn sys_sleep(&mut self) -> SysResult {
+        let n = self.arg_i32(0);
+        let n = if n < 0 { 0 } else { n as usize };
+        clock_sleep(n).map(|_| 0)
+    }
+
+    fn sys_uptime(&mut self) -> SysResult {
+        Ok(clock_read())
+    }
+
+    fn sys_open(&mut self) -> SysResult {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        self.arg_str(0, &mut path)?;
+        let flags = self.arg_i32(1);
+
+        let file = File::open(&path, flags)?;
+        self.data.get_mut().alloc_fd(file).map_err(|_| "open: no free file descriptor")
+    }
+
+    fn sys_write(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let addr = self.arg_raw(1);
+        let count = self.arg_i32(2);
+        if count < 0 {
+            return Err("write: negative count")
+        }
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        file.write(addr, count as u32).map(|n| n as usize)
+    }
+
+    fn sys_mknod(&mut self) -> SysResult {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        self.arg_str(0, &mut path)?;
+        let major = self.arg_i32(1) as u16;
+        let minor = self.arg_i32(2) as u16;
+
+        LOG.begin_op();
+        let res = create(&path, InodeType::Device, major, minor).map(drop);
+        LOG.end_op();
+        res.map(|_| 0)
+    }
+
+    fn sys_unlink(&mut self) -> SysResult {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        self.arg_str(0, &mut path)?;
+
+        LOG.begin_op();
+        let res = unlink(&path);
+        LOG.end_op();
+        res.map(|_| 0)
+    }
+
+    fn sys_link(&mut self) -> SysResult {
+        let mut old: [u8; MAXPATH] = [0; MAXPATH];
+        let mut new: [u8; MAXPATH] = [0; MAXPATH];
+        self.arg_str(0, &mut old)?;
+        self.arg_str(1, &mut new)?;
+
+        LOG.begin_op();
+        let res = link(&old, &new);
+        LOG.end_op();
+        res.map(|_| 0)
+    }
+
+    fn sys_mkdir(&mut self) -> SysResult {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        self.arg_str(0, &mut path)?;
+
+        LOG.begin_op();
+        let res = create(&path, InodeType::Directory, 0, 0).map(drop);
+        LOG.end_op();
+        res.map(|_| 0)
+    }
+
+    fn sys_close(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let file = self.data.get_mut().take_file(fd);
+        if let Some(ep) = file.as_ref().and_then(|file| file.as_endpoint().ok()) {
+            self.ipc_closed(ep);
+        }
+        drop(file);
+        Ok(0)
+    }
+
+    fn sys_mmap(&mut self) -> SysResult {
+        // the address hint in arg 0 is ignored,
+        // the kernel always picks one
+        let len = self.arg_raw(1);
+        let prot = self.arg_i32(2);
+        let flags = self.arg_i32(3);
+        let offset = self.arg_raw(5);
+
+        if len == 0 {
+            return Err("mmap: zero length")
+        }
+        let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+            MAP_SHARED => true,
+            MAP_PRIVATE => false,
+            _ => return Err("mmap: exactly one of MAP_SHARED and MAP_PRIVATE"),
+        };
+        if offset % PGSIZE != 0 {
+            return Err("mmap: offset not aligned")
+        }
+
+        let file = if flags & MAP_ANONYMOUS > 0 {
+            None
+        } else {
+            let fd = self.arg_fd(4)?;
+            let file = self.data.get_mut().get_file(fd).unwrap().clone();
+            file.mappable(shared && prot & PROT_WRITE > 0)?;
+            match offset.checked_add(len) {
+                Some(end) if end <= u32::MAX as usize => {},
+                _ => return Err("mmap: file range too large"),
+            }
+            Some(file)
         };
-        match self.arg_str(0, &mut path) {
-            Ok(_) => {
-                // debug
-                print!("sys_exec: ");
-                for c in path.iter() {
-                    if *c == 0 {
-                        break
-                    }
-                    print!("{}", *c as char);
+
+        self.data.get_mut().mmap(len, prot, shared, file, offset)
+    }
+
+    fn sys_munmap(&mut self) -> SysResult {
+        let addr = self.arg_raw(0);
+        let len = self.arg_raw(1);
+        self.data.get_mut().munmap(addr, len).map(|_| 0)
+    }
+
+    fn sys_sigalarm(&mut self) -> SysResult {
+        let interval = self.arg_i32(0);
+        let handler = self.arg_raw(1);
+        if interval < 0 {
+            return Err("sigalarm: negative interval")
+        }
+        self.data.get_mut().set_alarm(interval as usize, handler);
+        Ok(0)
+    }
+
+    fn sys_sigreturn(&mut self) -> SysResult {
+        self.data.get_mut().alarm_return()
+    }
+
+    fn sys_sigaction(&mut self) -> SysResult {
+        let sig = self.arg_i32(0);
+        let act = self.arg_raw(1);
+        let oldact = self.arg_raw(2);
+        if sig < 0 || !sig_catchable(sig as usize) {
+            return Err("sigaction: invalid signal")
+        }
+        let sig = sig as usize;
+        let size = mem::size_of::<SigAction>();
+
+        // copy the new action in before changing anything
+        let mut new = None;
+        if act != 0 {
+            let mut action = SigAction { handler: SIG_DFL, restorer: 0, mask: 0 };
+            self.data.get_mut().copy_in(act, &mut action as *mut SigAction as *mut u8, size)?;
+            new = Some(action);
+        }
+
+        let mut guard = self.excl.lock();
+        let old = guard.signals.get_action(sig);
+        if let Some(action) = new {
+            guard.signals.set_action(sig, action);
+        }
+        drop(guard);
+
+        if oldact != 0 {
+            self.data.get_mut().copy_out(&old as *const SigAction as *const u8, oldact, size)?;
+        }
+        Ok(0)
+    }
+
+    fn sys_sigprocmask(&mut self) -> SysResult {
+        let how = self.arg_i32(0);
+        let set = self.arg_raw(1);
+        let oldset = self.arg_raw(2);
+        let size = mem::size_of::<u32>();
+
+        let mut mask: u32 = 0;
+        if set != 0 {
+            self.data.get_mut().copy_in(set, &mut mask as *mut u32 as *mut u8, size)?;
+        }
+
+        let mut guard = self.excl.lock();
+        let old = guard.signals.get_blocked();
+        if set != 0 {
+            let blocked = match how {
+                SIG_BLOCK => old | mask,
+                SIG_UNBLOCK => old & !mask,
+                SIG_SETMASK => mask,
+                _ => {
+                    drop(guard);
+                    return Err("sigprocmask: invalid how")
                 }
-                println!();
+            };
+            guard.signals.set_blocked(blocked);
+        }
+        drop(guard);
+
+        if oldset != 0 {
+            self.data.get_mut().copy_out(&old as *const u32 as *const u8, oldset, size)?;
+        }
+        Ok(0)
+    }
+
+    fn sys_rt_sigreturn(&mut self) -> SysResult {
+        self.sig_return()
+    }
+
+    fn sys_clone(&mut self) -> SysResult {
+        let func = self.arg_raw(0);
+        let stack = self.arg_raw(1);
+        let arg = self.arg_raw(2);
+        self.clone(func, stack, arg)
+    }
+
+    fn sys_join(&mut self) -> SysResult {
+        let addr = self.arg_raw(0);
+        self.join(addr)
+    }
+
+    fn sys_settickets(&mut self) -> SysResult {
+        let tickets = self.arg_i32(0);
+        if tickets <= 0 {
+            return Err("settickets: tickets not positive")
+        }
+        self.set_tickets(tickets as usize).map(|_| 0)
+    }
+
+    /// Return the clock ticks the process pid has run for,
+    /// or the calling process if pid is 0.
+    fn sys_getticks(&mut self) -> SysResult {
+        let pid = self.arg_pid(0)?;
+        unsafe { PROC_MANAGER.ticks(pid) }
+    }
+
+    /// Set the harts the process pid, or the calling process if pid is 0,
+    /// may run on to mask, a bit per hart.
+    fn sys_sched_setaffinity(&mut self) -> SysResult {
+        let pid = self.arg_pid(0)?;
+        let mask = self.arg_raw(1);
+        unsafe { PROC_MANAGER.set_affinity(pid, mask).map(|_| 0) }
+    }
+
+    /// Return the affinity mask of the process pid,
+    /// or the calling process if pid is 0.
+    fn sys_sched_getaffinity(&mut self) -> SysResult {
+        let pid = self.arg_pid(0)?;
+        unsafe { PROC_MANAGER.get_affinity(pid) }
+    }
+
+    /// Sleep for the time in the timespec at req.
+    /// If interrupted while sleeping, the time left is written to rem unless it is 0.
+    fn sys_nanosleep(&mut self) -> SysResult {
+        let req = self.arg_raw(0);
+        let rem = self.arg_raw(1);
+        let size = mem::size_of::<TimeSpec>();
+        let mut ts = TimeSpec { sec: 0, nsec: 0 };
+        self.data.get_mut().copy_in(req, &mut ts as *mut TimeSpec as *mut u8, size)?;
+        let deadline = timer::now().checked_add(ts.to_cycles()?)
+            .ok_or("nanosleep: time too large")?;
+
+        if let Err(err) = timer::sleep_until(deadline) {
+            if rem != 0 {
+                let left = TimeSpec::from_cycles(deadline.saturating_sub(timer::now()));
+                self.data.get_mut().copy_out(&left as *const TimeSpec as *const u8, rem, size)?;
             }
-            Err(str) => {
-                println!("sys_exec1: {}", str);
-                return usize::MAX;
+            return Err(err)
+        }
+        Ok(0)
+    }
+
+    /// Write the time of the clock to the timespec at tp.
+    /// Only CLOCK_MONOTONIC, the time since boot, is supported.
+    fn sys_clock_gettime(&mut self) -> SysResult {
+        let clock = self.arg_i32(0);
+        let tp = self.arg_raw(1);
+        if clock != CLOCK_MONOTONIC {
+            return Err("clock_gettime: clock not supported")
+        }
+        let ts = TimeSpec::from_cycles(timer::now());
+        self.data.get_mut().copy_out(&ts as *const TimeSpec as *const u8, tp, mem::size_of::<TimeSpec>())?;
+        Ok(0)
+    }
+
+    /// futex(addr, FUTEX_WAIT, val, timeout) sleeps while the word at addr is val,
+    /// for at most the time in the timespec at timeout unless it is 0.
+    /// futex(addr, FUTEX_WAKE, n) wakes up at most n waiters,
+    /// and returns how many are woken up.
+    fn sys_futex(&mut self) -> SysResult {
+        let addr = self.arg_raw(0);
+        let op = self.arg_i32(1);
+        let val = self.arg_raw(2);
+        match op {
+            FUTEX_WAIT => {
+                let timeout = self.arg_raw(3);
+                let timeout = if timeout != 0 {
+                    let mut ts = TimeSpec { sec: 0, nsec: 0 };
+                    self.data.get_mut().copy_in(timeout, &mut ts as *mut TimeSpec as *mut u8,
+                        mem::size_of::<TimeSpec>())?;
+                    Some(ts.to_cycles()?)
+                } else {
+                    None
+                };
+                self.futex_wait(addr, val as u32, timeout).map(|_| 0)
             }
+            FUTEX_WAKE => self.futex_wake(addr, val as u32 as usize),
+            _ => Err("futex: invalid op"),
         }
+    }
+
+    /// Return the id of the shared memory segment of key,
+    /// creating it of size bytes if there is none, or if key is IPC_PRIVATE.
+    fn sys_shmget(&mut self) -> SysResult {
+        let key = self.arg_raw(0);
+        let size = self.arg_raw(1);
+        shm_get(key, size)
+    }
+
+    /// Attach the shared memory segment of id, and return its address.
+    fn sys_shmat(&mut self) -> SysResult {
+        let id = self.arg_raw(0);
+        // the address hint in arg 1 is ignored,
+        // the kernel always picks one as mmap
+        self.shm_attach(id)
+    }
 
-        // tmp ignore argv, i.e., only have path as first argument
-        // also not copy any content from user to kernel
-        // let mut argv: [*mut u8; MAXARG] = [ptr::null_mut(); MAXARG];
-        // let argv_addr: *const usize = self.arg_addr(1);
-        // argv[0] = unsafe {*argv_addr} as *mut u8;
-        // argv[1] = unsafe {*argv_addr.offset(1)} as *mut u8;
-        // if argv[1] as usize != 0 {
-        //     panic!("argv[1] is not 0, is {}...", unsafe {*argv[1]});
-        // }
-        // argv[1] = ptr::null_mut();
+    fn sys_shmdt(&mut self) -> SysResult {
+        let addr = self.arg_raw(0);
+        self.shm_detach(addr).map(|_| 0)
+    }
+
+    /// Only IPC_RMID is supported.
+    fn sys_shmctl(&mut self) -> SysResult {
+        let id = self.arg_raw(0);
+        let cmd = self.arg_i32(1);
+        match cmd {
+            IPC_RMID => shm_remove(id).map(|_| 0),
+            _ => Err("shmctl: invalid cmd"),
+        }
+    }
+
+    /// Create an IPC endpoint, and return its file descriptor.
+    fn sys_endpoint_create(&mut self) -> SysResult {
+        let file = File::endpoint()?;
+        self.data.get_mut().alloc_fd(file).map_err(|_| "endpoint_create: no free file descriptor")
+    }
+
+    /// send(ep, buf, len, w0, w1, w2) sends a message to the endpoint ep,
+    /// and returns once a receiver takes it.
+    fn sys_send(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        let msg = self.arg_msg()?;
+        self.ipc_send(file.as_endpoint()?, msg, false).map(|_| 0)
+    }
+
+    /// recv(ep, buf, len) receives a message from the endpoint ep,
+    /// see Proc::put_msg for where it goes, and returns the pid of the sender.
+    fn sys_recv(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        self.ipc_recv(file.as_endpoint()?)
+    }
 
-        // TODO - ELF load
-        panic!("sys_exec: end");
+    /// call(ep, buf, len, w0, w1, w2) sends a message as send,
+    /// and waits for the reply, which is received in place of the message
+    /// as recv, with the data truncated to len bytes.
+    fn sys_call(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        let msg = self.arg_msg()?;
+        let reply = self.ipc_send(file.as_endpoint()?, msg, true)?;
+        self.put_msg(&reply.unwrap())?;
+        Ok(0)
+    }
+
+    /// reply(pid, buf, len, w0, w1, w2) replies a message to the process pid,
+    /// which is waiting in call for the message received from it.
+    fn sys_reply(&mut self) -> SysResult {
+        let pid = self.arg_raw(0);
+        let msg = self.arg_msg()?;
+        self.ipc_reply(pid, msg).map(|_| 0)
+    }
+
+    /// Create a socket, and return its file descriptor.
+    /// Only AF_UNIX is supported, of type SOCK_STREAM or SOCK_DGRAM.
+    fn sys_socket(&mut self) -> SysResult {
+        let domain = self.arg_i32(0);
+        let stype = self.arg_i32(1);
+        if domain != AF_UNIX {
+            return Err("socket: domain not supported")
+        }
+        let file = File::socket(stype)?;
+        self.data.get_mut().alloc_fd(file).map_err(|_| "socket: no free file descriptor")
+    }
+
+    fn sys_bind(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        self.arg_str(1, &mut path)?;
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        Socket::bind(file.as_socket()?, &path).map(|_| 0)
+    }
+
+    fn sys_listen(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let backlog = self.arg_i32(1);
+        let backlog = if backlog < 0 { 0 } else { backlog as usize };
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        file.as_socket()?.listen(backlog).map(|_| 0)
+    }
+
+    /// Wait for a connection on a listening socket,
+    /// and return the file descriptor of the new socket connected to it.
+    fn sys_accept(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        let conn = file.accept()?;
+        self.data.get_mut().alloc_fd(conn).map_err(|_| "accept: no free file descriptor")
+    }
+
+    fn sys_connect(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        self.arg_str(1, &mut path)?;
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        Socket::connect(file.as_socket()?, &path).map(|_| 0)
+    }
+
+    /// sendto(fd, buf, len, path) sends to the datagram socket bound to path,
+    /// or to the connected socket if path is 0.
+    /// send(fd, buf, len) of libc is sendto with path 0.
+    fn sys_sendto(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let addr = self.arg_raw(1);
+        let count = self.arg_i32(2);
+        if count < 0 {
+            return Err("sendto: negative count")
+        }
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let path = if self.arg_raw(3) != 0 {
+            self.arg_str(3, &mut path)?;
+            Some(&path[..])
+        } else {
+            None
+        };
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        file.as_socket()?.send(addr, count as u32, path).map(|n| n as usize)
+    }
+
+    /// recvfrom(fd, buf, len) receives from a socket.
+    /// The address of the sender is not reported,
+    /// so recv(fd, buf, len) of libc is the same.
+    fn sys_recvfrom(&mut self) -> SysResult {
+        let fd = self.arg_fd(0)?;
+        let addr = self.arg_raw(1);
+        let count = self.arg_i32(2);
+        if count < 0 {
+            return Err("recvfrom: negative count")
+        }
+        let file = self.data.get_mut().get_file(fd).unwrap().clone();
+        file.as_socket()?.recv(addr, count as u32).map(|n| n as usize)
     }
 }
@@ -1,4 +1,5 @@
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct TrapFrame {
     /*   0 */ pub kernel_satp: usize,   // kernel page table
     /*   8 */ pub kernel_sp: usize,     // top of process's kernel stack
@@ -43,4 +44,20 @@ impl TrapFrame {
     pub fn admit_ecall(&mut self) {
         self.epc += 4;
     }
+
+    /// Zero the kernel fields, e.g., of a copy saved on the user stack.
+    pub fn clear_kernel(&mut self) {
+        self.kernel_satp = 0;
+        self.kernel_sp = 0;
+        self.kernel_trap = 0;
+        self.kernel_hartid = 0;
+    }
+
+    /// Take the kernel fields from other, e.g., for a copy restored from the user stack.
+    pub fn keep_kernel(&mut self, other: &Self) {
+        self.kernel_satp = other.kernel_satp;
+        self.kernel_sp = other.kernel_sp;
+        self.kernel_trap = other.kernel_trap;
+        self.kernel_hartid = other.kernel_hartid;
+    }
 }
@@ -0,0 +1,395 @@
+//! Virtual memory areas created by mmap or shmat
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use crate::consts::{PGSIZE, PROT_EXEC, PROT_READ, PROT_WRITE};
+use crate::fs::File;
+use crate::mm::{Addr, Address, PageTable, PhysAddr, PteFlag, RawPage, VirtAddr, pg_round_down};
+use crate::spinlock::SpinLock;
+use super::shm::Segment;
+
+/// The pages of a MAP_SHARED area, by page index in the file or anonymous memory,
+/// so that every address space mapping it maps the same pages.
+/// An anonymous one is shared by the processes forked after mmap,
+/// a file-backed one by all the areas mapping the file, see PageSet::of_file.
+/// The set holds a reference to each of its pages until it is dropped.
+pub struct PageSet {
+    pages: SpinLock<BTreeMap<usize, usize>>,
+    /// device and inode number of the file, None if anonymous
+    file: Option<(u32, u32)>,
+}
+
+/// The page sets of the files mapped MAP_SHARED.
+static FILE_PAGES: SpinLock<Vec<((u32, u32), Weak<PageSet>)>> =
+    SpinLock::new(Vec::new(), "file pages");
+
+impl PageSet {
+    fn new(file: Option<(u32, u32)>) -> Self {
+        Self {
+            pages: SpinLock::new(BTreeMap::new(), "page set"),
+            file,
+        }
+    }
+
+    /// The page set of an anonymous area.
+    pub fn anonymous() -> Arc<Self> {
+        Arc::new(Self::new(None))
+    }
+
+    /// The page set of the file, which is the same for every area mapping it.
+    pub fn of_file(file: &File) -> Result<Arc<Self>, &'static str> {
+        let key = file.inode_key().ok_or("mmap: not an inode file")?;
+        let mut sets = FILE_PAGES.lock();
+        if let Some(set) = sets.iter().find(|set| set.0 == key).and_then(|set| set.1.upgrade()) {
+            drop(sets);
+            return Ok(set)
+        }
+        let set = Arc::new(Self::new(Some(key)));
+        sets.retain(|set| set.0 != key);
+        sets.push((key, Arc::downgrade(&set)));
+        drop(sets);
+        Ok(set)
+    }
+
+    /// Return the page at index with a reference taken for the caller,
+    /// or None if it is not filled in yet.
+    fn get(&self, index: usize) -> Option<usize> {
+        let pages = self.pages.lock();
+        let pa = pages.get(&index).copied();
+        if let Some(pa) = pa {
+            RawPage::share(pa);
+        }
+        drop(pages);
+        pa
+    }
+
+    /// Add the page mem at index, unless another one was added meanwhile,
+    /// in which case mem is freed.
+    /// Return the page at index with a reference taken for the caller.
+    fn insert(&self, index: usize, mem: usize) -> usize {
+        let mut pages = self.pages.lock();
+        let pa = *pages.entry(index).or_insert(mem);
+        RawPage::share(pa);
+        drop(pages);
+        if pa != mem {
+            unsafe { RawPage::from_raw_and_drop(mem); }
+        }
+        pa
+    }
+}
+
+impl Drop for PageSet {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            FILE_PAGES.lock().retain(|set| set.1.strong_count() > 0);
+        }
+        for &pa in self.pages.lock().values() {
+            unsafe { RawPage::put(pa); }
+        }
+    }
+}
+
+/// A region of user memory mapped by mmap.
+/// Its pages are filled in at page fault,
+/// either zeroed or read from the file,
+/// and kept in a page set if it is a MAP_SHARED area.
+/// A shared memory segment attached by shmat is mapped as a whole instead.
+#[derive(Clone)]
+pub struct Vma {
+    start: usize,
+    end: usize,
+    perm: PteFlag,
+    shared: bool,
+    file: Option<Arc<File>>,
+    /// file offset mapped at start,
+    /// or segment offset if it is a shared memory segment
+    offset: usize,
+    /// the pages of a MAP_SHARED area
+    pages: Option<Arc<PageSet>>,
+    segment: Option<Arc<Segment>>,
+}
+
+impl Vma {
+    /// start and len must be page-aligned.
+    pub fn new(start: usize, len: usize, prot: i32, shared: bool,
+        file: Option<Arc<File>>, offset: usize) -> Result<Self, &'static str>
+    {
+        let mut perm = PteFlag::empty();
+        if prot & PROT_READ > 0 {
+            perm |= PteFlag::R;
+        }
+        if prot & PROT_WRITE > 0 {
+            // riscv has no write-only pages
+            perm |= PteFlag::R | PteFlag::W;
+        }
+        if prot & PROT_EXEC > 0 {
+            perm |= PteFlag::X;
+        }
+
+        let pages = match (shared, file.as_ref()) {
+            (false, _) => None,
+            (true, Some(file)) => Some(PageSet::of_file(file)?),
+            (true, None) => Some(PageSet::anonymous()),
+        };
+
+        Ok(Self {
+            start,
+            end: start + len,
+            perm,
+            shared,
+            file,
+            offset,
+            pages,
+            segment: None,
+        })
+    }
+
+    /// Create an area at start for the shared memory segment,
+    /// readable and writable.
+    pub fn attach(start: usize, segment: Arc<Segment>) -> Self {
+        Self {
+            start,
+            end: start + segment.size(),
+            perm: PteFlag::R | PteFlag::W,
+            shared: true,
+            file: None,
+            offset: 0,
+            pages: None,
+            segment: Some(segment),
+        }
+    }
+
+    /// Check if it is a shared memory segment attached at start.
+    #[inline]
+    pub fn is_attached_at(&self, start: usize) -> bool {
+        self.segment.is_some() && self.start == start
+    }
+
+    /// Map all the pages of the shared memory segment,
+    /// each taking a reference to its page.
+    /// Unmap those mapped on failure.
+    pub fn map_segment(&self, pagetable: &mut PageTable) -> Result<(), &'static str> {
+        let segment = self.segment.as_ref().expect("map_segment: not a segment");
+        for page in (self.start..self.end).step_by(PGSIZE) {
+            let pa = segment.page((self.offset + page - self.start) / PGSIZE);
+            RawPage::share(pa);
+            if let Err(err) = pagetable.map_pages(
+                VirtAddr::try_from(page).unwrap(),
+                PGSIZE,
+                PhysAddr::try_from(pa).unwrap(),
+                self.perm | PteFlag::U)
+            {
+                unsafe { RawPage::put(pa); }
+                pagetable.uvm_unmap(self.start, (page - self.start) / PGSIZE, true);
+                return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    #[inline]
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    #[inline]
+    pub fn contains(&self, va: usize) -> bool {
+        va >= self.start && va < self.end
+    }
+
+    /// Check if its pages are shared with other address spaces mapping it,
+    /// by MAP_SHARED or shmat.
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    /// Index of the page at va in the page set.
+    #[inline]
+    fn index(&self, va: usize) -> usize {
+        (self.offset + (pg_round_down(va) - self.start)) / PGSIZE
+    }
+
+    /// Handle a page fault at va in this area.
+    /// Map the page in the page set if it is a shared area having it,
+    /// or a zeroed page if it is an anonymous area and the page is not mapped yet,
+    /// or break the copy-on-write sharing of a private area after fork.
+    /// Return true if the page is to be filled in from the file instead,
+    /// see fill, which reads the file and so must not hold the address space locked.
+    pub fn fault(&self, pagetable: &mut PageTable, va: usize, store: bool)
+        -> Result<bool, &'static str>
+    {
+        if !self.perm.contains(PteFlag::R) {
+            return Err("mmap: access to an inaccessible mapping")
+        }
+        if store && !self.perm.contains(PteFlag::W) {
+            return Err("mmap: write to a read-only mapping")
+        }
+
+        let page = pg_round_down(va);
+        match pagetable.walk_flags(page) {
+            Some(flags) if store && flags.contains(PteFlag::COW) => {
+                return pagetable.cow_fault(page).map(|_| false)
+            }
+            Some(_) => return Err("mmap: access not permitted"),
+            None => {},
+        }
+
+        if let Some(pa) = self.pages.as_ref().and_then(|pages| pages.get(self.index(page))) {
+            return self.map_page(pagetable, page, pa).map(|_| false)
+        }
+        if self.file.is_some() {
+            return Ok(true)
+        }
+        let mem = unsafe { RawPage::try_new_zeroed() }
+            .map_err(|_| "mmap: out of memory")?;
+        self.map(pagetable, page, mem).map(|_| false)
+    }
+
+    /// Allocate a page filled in with the file content at va.
+    pub fn fill(&self, va: usize) -> Result<usize, &'static str> {
+        let file = self.file.as_ref().expect("fill: not a file mapping");
+        let mem = unsafe { RawPage::try_new_zeroed() }
+            .map_err(|_| "mmap: out of memory")?;
+        let offset = self.offset + (pg_round_down(va) - self.start);
+        if let Err(err) = u32::try_from(offset)
+            .map_err(|_| "mmap: offset too large")
+            .and_then(|offset| file.read_at(Address::Kernel(mem as *const u8), offset, PGSIZE as u32))
+        {
+            unsafe { RawPage::from_raw_and_drop(mem); }
+            return Err(err)
+        }
+        Ok(mem)
+    }
+
+    /// Map the physical page mem newly filled in at the page containing va,
+    /// after adding it to the page set if it is a shared area,
+    /// where the page another process added meanwhile is used instead.
+    /// mem is freed on failure.
+    pub fn map(&self, pagetable: &mut PageTable, va: usize, mem: usize)
+        -> Result<(), &'static str>
+    {
+        let page = pg_round_down(va);
+        let pa = match self.pages.as_ref() {
+            Some(pages) => pages.insert(self.index(page), mem),
+            None => mem,
+        };
+        self.map_page(pagetable, page, pa)
+    }
+
+    /// Map the physical page pa at page, with the reference held by the caller,
+    /// which is dropped on failure.
+    fn map_page(&self, pagetable: &mut PageTable, page: usize, pa: usize)
+        -> Result<(), &'static str>
+    {
+        if let Err(err) = pagetable.map_pages(
+            VirtAddr::try_from(page).unwrap(),
+            PGSIZE,
+            PhysAddr::try_from(pa).unwrap(),
+            self.perm | PteFlag::U)
+        {
+            unsafe { RawPage::put(pa); }
+            return Err(err)
+        }
+        Ok(())
+    }
+
+    /// Unmap the pages in [start, end), which must be page-aligned
+    /// and at either end of this area, so that the area stays contiguous.
+    /// Return the dirty pages of a shared file mapping,
+    /// which the caller writes back once the address space is unlocked.
+    pub fn unmap(&mut self, pagetable: &mut PageTable, start: usize, end: usize)
+        -> Result<Option<WriteBack>, &'static str>
+    {
+        if start < self.start || end > self.end || start >= end {
+            return Err("munmap: range not in the mapping")
+        }
+        if start != self.start && end != self.end {
+            return Err("munmap: cannot punch a hole in the mapping")
+        }
+
+        let write_back = self.dirty_pages(pagetable, start, end);
+        pagetable.uvm_unmap(start, (end - start) / PGSIZE, true);
+
+        if start == self.start {
+            self.offset += end - start;
+            self.start = end;
+        } else {
+            self.end = start;
+        }
+        Ok(write_back)
+    }
+
+    /// Collect the dirty pages in [start, end) to write back to the file,
+    /// if this is a shared file mapping.
+    /// A reference to each page is taken, so it outlives the unmapping.
+    fn dirty_pages(&self, pagetable: &PageTable, start: usize, end: usize) -> Option<WriteBack> {
+        let file = match (self.shared, self.file.as_ref()) {
+            (true, Some(file)) => file,
+            _ => return None,
+        };
+
+        let mut pages = Vec::new();
+        for page in (start..end).step_by(PGSIZE) {
+            match pagetable.walk_flags(page) {
+                Some(flags) if flags.contains(PteFlag::D) => {},
+                _ => continue,
+            }
+            let pa = pagetable.walk_addr(VirtAddr::try_from(page).unwrap()).unwrap();
+            RawPage::share(pa.as_usize());
+            pages.push((pa.as_usize(), self.offset + (page - self.start)));
+        }
+        Some(WriteBack {
+            file: Arc::clone(file),
+            pages,
+        })
+    }
+
+    /// Share the mapped pages of this area with a child's page table at fork.
+    /// A private area's writable pages become copy-on-write.
+    pub fn copy(&self, pagetable: &mut PageTable, child: &mut PageTable)
+        -> Result<(), &'static str>
+    {
+        pagetable.uvm_share(child, self.start, self.end, !self.shared)
+    }
+}
+
+/// Dirty pages of a shared file mapping taken out by Vma::unmap,
+/// with a reference held to each of them.
+pub struct WriteBack {
+    file: Arc<File>,
+    /// physical address and file offset of each page
+    pages: Vec<(usize, usize)>,
+}
+
+impl WriteBack {
+    /// Write the pages back to the file and drop the references.
+    /// It reads the file, so the address space must not be locked,
+    /// unless no other thread uses it.
+    /// The pages after a failed one are still written back,
+    /// and the first error is returned.
+    pub fn write(self) -> Result<(), &'static str> {
+        let mut res = Ok(());
+        for &(pa, offset) in self.pages.iter() {
+            let written = self.file.write_at(Address::Kernel(pa as *const u8), offset as u32, PGSIZE as u32);
+            if let (Err(err), Ok(())) = (written, res) {
+                res = Err(err);
+            }
+            unsafe { RawPage::put(pa); }
+        }
+        res
+    }
+}
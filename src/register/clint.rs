@@ -9,12 +9,14 @@ use core::convert::Into;
 use crate::consts::{CLINT_MTIME, CLINT_MTIMECMP};
 
 #[inline]
-unsafe fn read_mtime() -> u64 {
+pub unsafe fn read_mtime() -> u64 {
     ptr::read_volatile(Into::<usize>::into(CLINT_MTIME) as *const u64)
 }
 
+/// Set the time of the next timer interrupt of hart mhartid,
+/// u64::MAX for none.
 #[inline]
-unsafe fn write_mtimecmp(mhartid: usize, value: u64) {
+pub unsafe fn write_mtimecmp(mhartid: usize, value: u64) {
     //每个core的CLINT_MTIMECMP地址有偏移
     let offset = Into::<usize>::into(CLINT_MTIMECMP) + 8 * mhartid;
     ptr::write_volatile(offset as *mut u64, value);
@@ -71,9 +71,8 @@ pub unsafe fn rust_main() -> ! {
         trap_init_hart(); // install kernel trap vector
         //调用kernelvec 处理interrupts和exceptions
         plic::init_hart(cpuid); // ask PLIC for device interrupts
-
-        // LTODO - init other things
-        loop {}
+        // the timer is set up by start() on every hart,
+        // so this hart is ready to run processes
     }
 
     #[cfg(feature = "unit_test")]
@@ -1,6 +1,6 @@
 use core::convert::Into;//强制转换的库
 
-use crate::{consts::{CLINT_MTIMECMP, NCPU}, register::sie};
+use crate::{consts::{CLINT_MTIMECMP, NCPU, TIMER_INTERVAL}, register::sie};
 use crate::register::{
     clint, medeleg, mepc, mhartid, mideleg, mie, mscratch, mstatus, mtvec, satp, tp,
 };
@@ -58,14 +58,15 @@ unsafe fn timerinit() {
     let id = mhartid::read();
 
     // ask the CLINT for a timer interrupt.
-    let interval: u64 = 1000000; // cycles; about 1/10th second in qemu.
+    let interval: u64 = TIMER_INTERVAL; // cycles; about 1/10th second in qemu.
     //设置时钟间隔
     clint::add_mtimecmp(id, interval);
 
     // prepare information in scratch[] for timervec.
     // scratch[0..3] : space for timervec to save registers.留出一片空间来保存将要使用的寄存器的值，类似栈的作用
     // scratch[4] : address of CLINT MTIMECMP register.
-    // scratch[5] : desired interval (in cycles) between timer interrupts.
+    // timervec disarms the timer after each interrupt,
+    // and the kernel arms it again for the next tick or timer due, see timer.rs.
     let offset = 32 * id;
 
     //我们通常用xlen表示整数寄存器位数或者说地址空间位数，所以对于RV32I, xlen=32, 对于RV64I, xlen=64。
@@ -73,13 +74,12 @@ unsafe fn timerinit() {
     //通常，它用于保存一个指向机器模式hart-local上下文空间的指针，
     //并在进入m模式trap处理程序时与用户寄存器交换
     MSCRATCH0[offset + 4] = 8 * id + Into::<usize>::into(CLINT_MTIMECMP);
-    MSCRATCH0[offset + 5] = interval as usize;
     mscratch::write((MSCRATCH0.as_ptr() as usize) + offset * core::mem::size_of::<usize>());
 
     // set the machine-mode trap handler.
     extern "C" {
         // 这里面会做如下操作，函数开始和结束先利用mscratch[0..3](stack)保存和恢复进行函数要用寄存器，懂汇编都知道
-        // 然后 CLINT_MTIMECMP += interval
+        // 然后 CLINT_MTIMECMP = u64::MAX, 关闭时钟中断, 由内核重新设置
         // 然后 sip 的第2位置为1，来raise a supervisor software interrupt
         fn timervec();
     }
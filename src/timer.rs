@@ -0,0 +1,366 @@
+//! Kernel timers
+//!
+//! Time is read from CLINT_MTIME, counting cycles of MTIME_FREQ since boot.
+//! Pending timers are kept in a hashed timer wheel, one slot per clock tick,
+//! and expired by clock_intr in trap.rs on any hart.
+//! Each hart arms its own CLINT_MTIMECMP, in supervisor mode,
+//! for its next clock tick or the earliest timer due, whichever comes first,
+//! and timervec in kernelvec.S disarms it after each interrupt.
+
+use array_macro::array;
+
+use crate::consts::{MTIME_FREQ, TIMER_INTERVAL};
+use crate::process::{CPU_MANAGER, CpuManager, PROC_MANAGER};
+use crate::register::clint;
+use crate::spinlock::SpinLock;
+
+/// Maximum number of pending timers.
+const NTIMER: usize = 128;
+
+/// Number of slots in the wheel, each covering one clock tick.
+const NSLOT: usize = 64;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// Return the current time, in cycles of mtime since boot.
+#[inline]
+pub fn now() -> u64 {
+    unsafe { clint::read_mtime() }
+}
+
+/// Time in seconds and nanoseconds, shared with user space.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TimeSpec {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+impl TimeSpec {
+    pub fn from_cycles(cycles: u64) -> Self {
+        Self {
+            sec: (cycles / MTIME_FREQ) as i64,
+            nsec: ((cycles % MTIME_FREQ) * NSEC_PER_SEC / MTIME_FREQ) as i64,
+        }
+    }
+
+    /// Convert to cycles of mtime, rounding up.
+    pub fn to_cycles(&self) -> Result<u64, &'static str> {
+        if self.sec < 0 || self.nsec < 0 || self.nsec as u64 >= NSEC_PER_SEC {
+            return Err("timespec: invalid")
+        }
+        let nsec_cycles = (self.nsec as u64 * MTIME_FREQ + NSEC_PER_SEC - 1) / NSEC_PER_SEC;
+        (self.sec as u64).checked_mul(MTIME_FREQ)
+            .and_then(|cycles| cycles.checked_add(nsec_cycles))
+            .ok_or("timespec: too large")
+    }
+}
+
+/// What to do when a timer expires.
+/// It is done in interrupt context, with no lock held.
+#[derive(Clone, Copy)]
+pub enum Expiry {
+    /// wake up the processes sleeping on the channel
+    Wakeup(usize),
+    /// call the function with the argument
+    Call(fn(usize), usize),
+}
+
+/// Handle of a pending timer, to cancel it.
+#[derive(Clone, Copy)]
+pub struct TimerId {
+    index: usize,
+    gen: usize,
+}
+
+struct Timer {
+    deadline: u64,
+    /// None if the entry is free
+    expiry: Option<Expiry>,
+    /// bumped each time the entry is freed, so a stale TimerId is ignored
+    gen: usize,
+    /// next timer in the same slot
+    next: Option<usize>,
+}
+
+impl Timer {
+    const fn new() -> Self {
+        Self {
+            deadline: 0,
+            expiry: None,
+            gen: 0,
+            next: None,
+        }
+    }
+}
+
+struct Wheel {
+    timers: [Timer; NTIMER],
+    /// heads of the timer lists, indexed by the tick of the deadline modulo NSLOT
+    slots: [Option<usize>; NSLOT],
+    /// the tick before which every expired timer is taken out
+    current: u64,
+}
+
+static WHEEL: SpinLock<Wheel> = SpinLock::new(Wheel::new(), "timer wheel");
+
+#[inline]
+fn tick_of(time: u64) -> u64 {
+    time / TIMER_INTERVAL
+}
+
+#[inline]
+fn slot_of(tick: u64) -> usize {
+    (tick % NSLOT as u64) as usize
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            timers: array![_ => Timer::new(); NTIMER],
+            slots: [None; NSLOT],
+            current: 0,
+        }
+    }
+
+    fn add(&mut self, deadline: u64, expiry: Expiry) -> Result<TimerId, &'static str> {
+        let index = self.timers.iter().position(|timer| timer.expiry.is_none())
+            .ok_or("add_timer: too many timers")?;
+        // a timer already due goes to the current slot, which is looked at first
+        let slot = slot_of(tick_of(deadline).max(self.current));
+        let timer = &mut self.timers[index];
+        timer.deadline = deadline;
+        timer.expiry = Some(expiry);
+        timer.next = self.slots[slot];
+        self.slots[slot] = Some(index);
+        Ok(TimerId { index, gen: timer.gen })
+    }
+
+    /// Unlink the timer at index, which is in slot, and free it.
+    fn remove(&mut self, slot: usize, index: usize) -> Expiry {
+        let next = self.timers[index].next;
+        if self.slots[slot] == Some(index) {
+            self.slots[slot] = next;
+        } else {
+            let mut prev = self.slots[slot].expect("timer wheel: not in slot");
+            while self.timers[prev].next != Some(index) {
+                prev = self.timers[prev].next.expect("timer wheel: not in slot");
+            }
+            self.timers[prev].next = next;
+        }
+        let timer = &mut self.timers[index];
+        timer.next = None;
+        timer.gen = timer.gen.wrapping_add(1);
+        timer.expiry.take().unwrap()
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        let timer = &self.timers[id.index];
+        if timer.gen != id.gen || timer.expiry.is_none() {
+            return false
+        }
+        let slot = (0..NSLOT)
+            .find(|&slot| self.iter(slot).any(|index| index == id.index))
+            .expect("timer wheel: timer lost");
+        self.remove(slot, id.index);
+        true
+    }
+
+    fn iter(&self, slot: usize) -> SlotIter<'_> {
+        SlotIter {
+            wheel: self,
+            next: self.slots[slot],
+        }
+    }
+
+    /// Take out a timer expired by the time now.
+    fn pop_expired(&mut self, now: u64) -> Option<Expiry> {
+        let now_tick = tick_of(now);
+        while self.current <= now_tick {
+            let slot = slot_of(self.current);
+            let expired = self.iter(slot).find(|&index| self.timers[index].deadline <= now);
+            if let Some(index) = expired {
+                return Some(self.remove(slot, index))
+            }
+            if self.current == now_tick {
+                break
+            }
+            // the slots skipped over are looked at once, since they repeat
+            self.current = if now_tick - self.current >= NSLOT as u64 {
+                now_tick - NSLOT as u64 + 1
+            } else {
+                self.current + 1
+            };
+        }
+        None
+    }
+
+    /// The earliest deadline of the pending timers, u64::MAX if none.
+    fn next_deadline(&self) -> u64 {
+        // look at a round of the wheel from the current tick on
+        for tick in self.current..self.current + NSLOT as u64 {
+            let due = self.iter(slot_of(tick))
+                .map(|index| self.timers[index].deadline)
+                .filter(|&deadline| tick_of(deadline) <= tick)
+                .min();
+            if let Some(due) = due {
+                return due
+            }
+        }
+        // every timer is at least a round away
+        self.timers.iter()
+            .filter(|timer| timer.expiry.is_some())
+            .map(|timer| timer.deadline)
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+struct SlotIter<'a> {
+    wheel: &'a Wheel,
+    next: Option<usize>,
+}
+
+impl<'a> Iterator for SlotIter<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let index = self.next?;
+        self.next = self.wheel.timers[index].next;
+        Some(index)
+    }
+}
+
+/// Start a timer, which does expiry at time deadline.
+/// It is done at the next clock tick, or earlier on an idle hart,
+/// so a caller wanting it exactly at deadline should go idle, e.g., sleep.
+pub fn add_timer(deadline: u64, expiry: Expiry) -> Result<TimerId, &'static str> {
+    WHEEL.lock().add(deadline, expiry)
+}
+
+/// Stop a timer.
+/// Return false if it has expired or been cancelled already.
+pub fn cancel(id: TimerId) -> bool {
+    WHEEL.lock().cancel(id)
+}
+
+/// Do the expiry of the timers expired by the time now.
+/// Called by clock_intr on timer interrupts.
+pub fn expire(now: u64) {
+    loop {
+        let expiry = WHEEL.lock().pop_expired(now);
+        match expiry {
+            Some(Expiry::Wakeup(channel)) => unsafe { PROC_MANAGER.wakeup(channel) },
+            Some(Expiry::Call(func, arg)) => func(arg),
+            None => break,
+        }
+    }
+}
+
+/// Sleep the current process until the time deadline.
+/// Return Err if it is killed or interrupted by a caught signal while sleeping.
+pub fn sleep_until(deadline: u64) -> Result<(), &'static str> {
+    let p = unsafe { CPU_MANAGER.my_proc() };
+    let channel = p.wait_channel();
+    while now() < deadline {
+        if p.interrupted() {
+            return Err("sleep: interrupted")
+        }
+        let mut wheel = WHEEL.lock();
+        let id = wheel.add(deadline, Expiry::Wakeup(channel))?;
+        // the wheel lock is held until the process is asleep,
+        // so the timer can not expire before
+        p.sleep(channel, wheel);
+        cancel(id);
+    }
+    Ok(())
+}
+
+/// Arm this hart's timer for the earliest timer due,
+/// and for the next clock tick too if a process is running on it,
+/// or always unless tickless.
+/// Interrupts must be disabled.
+pub fn arm(running: bool) {
+    let mut cmp = WHEEL.lock().next_deadline();
+    if running || !cfg!(feature = "tickless") {
+        cmp = cmp.min((tick_of(now()) + 1) * TIMER_INTERVAL);
+    }
+    unsafe { clint::write_mtimecmp(CpuManager::cpu_id(), cmp); }
+}
+
+/// Make the hart id take a timer interrupt at once,
+/// e.g., to leave wfi in idle.
+pub fn kick(id: usize) {
+    unsafe { clint::write_mtimecmp(id, 0); }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Too large for a kernel stack, and shared by the tests below,
+    /// each of which leaves it with no timer pending.
+    static TEST_WHEEL: SpinLock<Wheel> = SpinLock::new(Wheel::new(), "test timer wheel");
+
+    /// Start time of a test, at the tick the wheel is up to.
+    fn base(wheel: &Wheel) -> u64 {
+        wheel.current * TIMER_INTERVAL
+    }
+
+    /// A timer a round or more away shares a slot with nearer ticks,
+    /// but does not expire at them.
+    pub fn far_deadline() {
+        let mut wheel = TEST_WHEEL.lock();
+        let base = base(&wheel);
+        let deadline = base + (NSLOT as u64 + 5) * TIMER_INTERVAL;
+        wheel.add(deadline, Expiry::Wakeup(1)).unwrap();
+        assert_eq!(wheel.next_deadline(), deadline);
+        assert!(wheel.pop_expired(base + 5 * TIMER_INTERVAL).is_none());
+        assert!(wheel.pop_expired(deadline - 1).is_none());
+        assert!(matches!(wheel.pop_expired(deadline), Some(Expiry::Wakeup(1))));
+        assert_eq!(wheel.next_deadline(), u64::MAX);
+        println!("timer far_deadline: pass");
+    }
+
+    /// A TimerId cancels its timer only once,
+    /// and not a later timer reusing the entry.
+    pub fn stale_cancel() {
+        let mut wheel = TEST_WHEEL.lock();
+        let deadline = base(&wheel) + TIMER_INTERVAL;
+        let id = wheel.add(deadline, Expiry::Wakeup(1)).unwrap();
+        assert!(wheel.cancel(id));
+        assert!(!wheel.cancel(id));
+        let reused = wheel.add(deadline, Expiry::Wakeup(2)).unwrap();
+        assert_eq!(reused.index, id.index);
+        assert!(!wheel.cancel(id));
+        assert_eq!(wheel.next_deadline(), deadline);
+        assert!(wheel.cancel(reused));
+        assert_eq!(wheel.next_deadline(), u64::MAX);
+        println!("timer stale_cancel: pass");
+    }
+
+    /// After a gap of several rounds, every timer due expires,
+    /// and one not due yet stays.
+    pub fn catch_up() {
+        let mut wheel = TEST_WHEEL.lock();
+        let base = base(&wheel);
+        let now = base + 5 * NSLOT as u64 * TIMER_INTERVAL;
+        for (n, &ticks) in [2, 10, 3 * NSLOT as u64].iter().enumerate() {
+            wheel.add(base + ticks * TIMER_INTERVAL, Expiry::Wakeup(n)).unwrap();
+        }
+        let later = wheel.add(now + TIMER_INTERVAL, Expiry::Wakeup(3)).unwrap();
+
+        let mut expired = [false; 3];
+        while let Some(expiry) = wheel.pop_expired(now) {
+            match expiry {
+                Expiry::Wakeup(n) if n < 3 && !expired[n] => expired[n] = true,
+                _ => panic!("timer catch_up: wrong timer expired"),
+            }
+        }
+        assert_eq!(expired, [true; 3]);
+        assert_eq!(wheel.current, tick_of(now));
+        assert_eq!(wheel.next_deadline(), now + TIMER_INTERVAL);
+        assert!(wheel.cancel(later));
+        println!("timer catch_up: pass");
+    }
+}
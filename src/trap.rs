@@ -1,13 +1,17 @@
 //! Trap handler between user/kernel space and kernel space
 //! Mostly adopted from xv6-riscv
 
-use crate::consts::{TRAMPOLINE, TRAPFRAME, UART0_IRQ, VIRTIO0_IRQ};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::consts::{TIMER_INTERVAL, TRAMPOLINE, UART0_IRQ, VIRTIO0_IRQ};
+use crate::consts::signal::{SIGILL, SIGSEGV};
 use crate::register::{stvec, sstatus, sepc, stval, sip,
     scause::{self, ScauseType}};
 use crate::process::{CPU_MANAGER, CpuManager};
-use crate::spinlock::SpinLock;
 use crate::plic;
+use crate::timer;
 use crate::driver::virtio_disk::DISK;
+use crate::console::uartintr;
 
 pub unsafe fn trap_init_hart() {
     extern "C" {
@@ -23,6 +27,7 @@ pub unsafe extern fn user_trap() {
     if !sstatus::is_from_user() {
         panic!("user_trap: not from user mode, sstatus={:#x}", sstatus::read());
     }
+    CPU_MANAGER.leave_user();
 
     // switch the trap handler to kerneltrap()
     extern "C" {fn kernelvec();}
@@ -30,14 +35,14 @@ pub unsafe extern fn user_trap() {
 
     let p = CPU_MANAGER.my_proc();
 
-    match scause::get_scause() {
+    let cause = scause::get_scause();
+    match cause {
         ScauseType::IntSExt => {
             // this is a supervisor external interrupt, via PLIC.
 
             let irq = plic::claim();
             if irq as usize == UART0_IRQ {
-                // TODO - uart intr
-                panic!("kerneltrap(): uart intr");
+                uartintr();
             } else if irq as usize == VIRTIO0_IRQ {
                 DISK.lock().intr();
             } else {
@@ -49,27 +54,36 @@ pub unsafe extern fn user_trap() {
         ScauseType::IntSSoft => {
             // software interrupt from a machine-mode timer interrupt,
             // forwarded by timervec in kernelvec.S.
-
-            // only cpu 0 inc ticks
-            if CpuManager::cpu_id() == 0 {
-                clock_intr();
+            if timer_intr() {
+                // the process consumed a tick in user mode
+                (*p.data.get()).alarm_tick();
+
+                // give up the cpu if the scheduling policy says so
+                p.check_abondon(-1);
+                if p.clock_tick() {
+                    p.yielding();
+                }
             }
-
-            // acknowledge the software interrupt
-            sip::clear_ssip();
-
-            // give up the cpu
-            p.check_abondon(-1);
-            p.yielding();
         }
         ScauseType::ExcUEcall => {
             p.check_abondon(-1);
             p.syscall();
+            p.check_abondon(-1);
+        }
+        ScauseType::ExcLoadPageFault | ScauseType::ExcStorePageFault => {
+            let va = stval::read();
+            let store = matches!(cause, ScauseType::ExcStorePageFault);
+            if let Err(err) = (*p.data.get()).page_fault(va, store) {
+                let pid = p.excl.lock().pid;
+                println!("pid {}: {}", pid, err);
+                println!("sepc={:#x} stval={:#x}", sepc::read(), va);
+                p.force_signal(SIGSEGV);
+            }
         }
         ScauseType::Unknown => {
             println!("scause {:#x}", scause::read());
             println!("sepc={:#x} stval={:#x}", sepc::read(), stval::read());
-            p.abondon(-1);
+            p.force_signal(SIGILL);
         }
     }
 
@@ -78,6 +92,9 @@ pub unsafe extern fn user_trap() {
 
 /// Return to user space
 pub unsafe fn user_trap_ret() -> ! {
+    // deliver pending signals, which might divert the return to a handler
+    CPU_MANAGER.my_proc().handle_signals();
+
     // disable interrupts and prepare sret to user mode
     sstatus::intr_off();
     sstatus::user_ret_prepare();
@@ -86,10 +103,11 @@ pub unsafe fn user_trap_ret() -> ! {
     stvec::write(TRAMPOLINE.into());
 
     // let the current process prepare for the sret
-    let satp = {
+    let (satp, tf_va) = {
         let pd = &mut *CPU_MANAGER.my_proc().data.get();
-        pd.user_ret_prepare()
+        (pd.user_ret_prepare(), pd.trapframe_va())
     };
+    CPU_MANAGER.enter_user(satp);
 
     // call userret with virtual address
     extern "C" {
@@ -99,7 +117,7 @@ pub unsafe fn user_trap_ret() -> ! {
     let distance = userret as usize - trampoline as usize;
     let userret_virt: extern "C" fn(usize, usize) -> ! =
         core::mem::transmute(Into::<usize>::into(TRAMPOLINE) + distance);
-    userret_virt(TRAPFRAME.into(), satp);
+    userret_virt(tf_va, satp);
 }
 
 /// Used to handle kernel space's trap
@@ -122,8 +140,7 @@ pub unsafe fn kerneltrap() {
 
             let irq = plic::claim();
             if irq as usize == UART0_IRQ {
-                // TODO - uart intr
-                panic!("kerneltrap(): uart intr");
+                uartintr();
             } else if irq as usize == VIRTIO0_IRQ {
                 DISK.lock().intr();
             }
@@ -133,21 +150,17 @@ pub unsafe fn kerneltrap() {
         ScauseType::IntSSoft => {
             // software interrupt from a machine-mode timer interrupt,
             // forwarded by timervec in kernelvec.S.
-
-            // only cpu 0 inc ticks
-            if CpuManager::cpu_id() == 0 {
-                clock_intr();
+            if timer_intr() {
+                // give up the cpu
+                CPU_MANAGER.my_cpu_mut().yield_proc();
             }
-
-            // acknowledge the software interrupt
-            sip::clear_ssip();
-
-            // give up the cpu
-            CPU_MANAGER.my_cpu_mut().yield_proc();
         }
         ScauseType::ExcUEcall => {
             panic!("kerneltrap(): ecall from supervisor mode");
         }
+        ScauseType::ExcLoadPageFault | ScauseType::ExcStorePageFault => {
+            panic!("kerneltrap(): page fault, stval={:#x}", stval::read());
+        }
         ScauseType::Unknown => {
             println!("scause {:#x}", scause::read());
             println!("sepc={:#x} stval={:#x}", sepc::read(), stval::read());
@@ -161,10 +174,44 @@ pub unsafe fn kerneltrap() {
     sstatus::write(local_sstatus);
 }
 
-static TICKS: SpinLock<usize> = SpinLock::new(0usize, "time");
+/// Clock ticks accounted to the scheduling policy, see CpuManager::sched_clock.
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Handle a timer interrupt, forwarded by timervec as a software interrupt.
+/// Return true if this hart has reached a new clock tick,
+/// rather than being interrupted for a timer due or kicked.
+unsafe fn timer_intr() -> bool {
+    let now = timer::now();
+    clock_intr(now);
+
+    // acknowledge the software interrupt
+    sip::clear_ssip();
+
+    // an idle hart arms its timer itself, see CpuManager::idle
+    let c = CPU_MANAGER.my_cpu_mut();
+    timer::arm(c.has_proc());
+    c.new_tick((now / TIMER_INTERVAL) as usize)
+}
+
+/// Called on timer interrupts of any hart.
+fn clock_intr(now: u64) {
+    let tick = (now / TIMER_INTERVAL) as usize;
+    let old = TICKS.fetch_max(tick, Ordering::Relaxed);
+    for _ in old..tick {
+        CpuManager::sched_clock();
+    }
+    timer::expire(now);
+}
+
+/// Return the number of clock ticks since boot.
+pub fn clock_read() -> usize {
+    (timer::now() / TIMER_INTERVAL) as usize
+}
 
-fn clock_intr() {
-    let mut _ticks = TICKS.lock();
-    *_ticks += 1;
-    drop(_ticks);
+/// Sleep the current process for count ticks.
+/// Return Err if it is killed or interrupted by a caught signal while sleeping.
+pub fn clock_sleep(count: usize) -> Result<(), &'static str> {
+    let due = (clock_read() as u64).saturating_add(count as u64)
+        .saturating_mul(TIMER_INTERVAL);
+    timer::sleep_until(due)
 }